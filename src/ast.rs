@@ -5,7 +5,6 @@ use crate::{
 };
 
 type PNode = Positioned<Node>;
-type PToken = Positioned<Token>;
 
 #[derive(Debug, Clone)]
 pub enum Node {
@@ -16,11 +15,85 @@ pub enum Node {
     Call { expr: Box<PNode>, args: Vec<PNode> },
     Tuple(Vec<PNode>),
     Function { name: String, params: Vec<String>, body: Box<PNode> },
-    Memory(Vec<Vec<String>>),
+    Memory { layer: Option<String>, rows: Vec<Vec<String>> },
     Definition { name: String, value: Box<PNode> },
-    Directional(String),
+    Sys(Vec<(String, PNode)>),
+    Directional { layer: Option<String>, name: String, arg: Option<Box<PNode>> },
+    Offset { layer: Option<String>, dx: Box<PNode>, dy: Box<PNode> },
+    /// `-expr` or `!expr`.
+    Unary { op: Token, expr: Box<PNode> },
+    Halt(Option<Box<PNode>>),
+    /// `if condition then a else b`, which only evaluates the branch taken.
+    If { condition: Box<PNode>, then: Box<PNode>, otherwise: Box<PNode> },
+    Neighbourhood { name: String, shape: Shape },
     Sum(Box<PNode>),
-    Main { centre: Box<PNode>, conditional: Option<Box<PNode>>, result: Box<PNode>, print: bool},
+    /// `[expr for name in source if filter]`, an array of `expr` for each item
+    /// of `source` that passes the filter.
+    Comprehension { expr: Box<PNode>, name: String, source: Box<PNode>, filter: Option<Box<PNode>> },
+    Count { binding: Option<(String, Box<PNode>)>, predicate: Box<PNode> },
+    Total { binding: Option<(String, Box<PNode>)>, expr: Box<PNode> },
+    /// `rule name { ... }`, a named group of main statements.
+    Rule { name: Positioned<String>, statements: Vec<PNode> },
+    Main {
+        centre: Box<PNode>,
+        conditional: Option<Box<PNode>>,
+        result: Box<PNode>,
+        print: bool,
+        target: Option<String>,
+    },
+}
+
+/// The largest radius a neighbourhood can have. Every cell reads every cell
+/// in its neighbourhood, so larger ones would take too long to compute.
+pub const MAX_RADIUS: usize = 64;
+
+/// The largest width and height a grid can have, so that a typo in a
+/// repeat count or size reports an error instead of running out of memory.
+pub const MAX_SIZE: usize = 4096;
+
+/// The cells making up a named neighbourhood.
+#[derive(Debug, Clone)]
+pub enum Shape {
+    /// Every cell within a square of the given radius.
+    Moore(usize),
+    /// Every cell within the given number of steps along the axes.
+    VonNeumann(usize),
+    /// Every cell within the given radius on a hexagonal grid, with rows
+    /// sheared so each cell touches its northeast and southwest neighbours.
+    Hex(usize),
+    Offsets(Vec<(isize, isize)>),
+}
+
+impl Node {
+    /// The expressions directly nested inside this node.
+    pub fn children(&self) -> Vec<&PNode> {
+        match self {
+            Node::Literal(_) | Node::Variable(_) | Node::Memory { .. } | Node::Neighbourhood { .. } => {
+                Vec::new()
+            }
+            Node::Directional { arg: expr, .. } | Node::Halt(expr) => {
+                expr.iter().map(|expr| &**expr).collect()
+            }
+            Node::Array(items) | Node::Tuple(items) | Node::Rule { statements: items, .. } => items.iter().collect(),
+            Node::Binary { left, right, .. } => vec![left, right],
+            Node::Call { expr, args } => std::iter::once(&**expr).chain(args).collect(),
+            Node::Function { body, .. } => vec![body],
+            Node::Definition { value, .. } => vec![value],
+            Node::Sys(settings) => settings.iter().map(|(_, value)| value).collect(),
+            Node::Offset { dx, dy, .. } => vec![dx, dy],
+            Node::If { condition, then, otherwise } => vec![condition, then, otherwise],
+            Node::Comprehension { expr, source, filter, .. } => {
+                [&**expr, &**source].into_iter().chain(filter.as_deref()).collect()
+            }
+            Node::Sum(expr) | Node::Unary { expr, .. } => vec![expr],
+            Node::Count { binding, predicate: expr } | Node::Total { binding, expr } => {
+                binding.iter().map(|(_, source)| &**source).chain([&**expr]).collect()
+            }
+            Node::Main { centre, conditional, result, .. } => {
+                std::iter::once(&**centre).chain(conditional.as_deref()).chain([&**result]).collect()
+            }
+        }
+    }
 }
 
 impl PNode {
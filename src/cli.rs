@@ -0,0 +1,256 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use crate::interpreter::UpdateMode;
+
+pub const USAGE: &str = "\
+Usage: lang [command] [options] <file>
+
+Commands:
+  run           Run a program (the default)
+  check         Lex, parse and load a program without running it
+  fmt           Print a program with normalised spacing
+  dump-tokens   Print the tokens of a program
+  dump-ast      Print the syntax tree of a program
+  export        Run a program and save its final grid as a PPM image
+
+Options:
+  --tick <ms>           Delay between generations in the terminal (default 200)
+  --generations <n>     Stop after n generations (default 1 with text or export)
+  --seed <n>            Seed the random numbers given by @random
+  --renderer <name>     How to show generations: terminal (default) or text
+  --headless[=n]        Same as --renderer text, stopping after n generations (default 1)
+  --every               With the text renderer, write every generation
+  --out <path>          Write grids or images to a file instead of stdout
+  --print-out <path>    With the text renderer, write $ output to a file
+  --in-place            Write cells as soon as they are computed
+  --set <name>=<value>  Override a constant defined with !
+  --sys <name>=<value>  Override a setting of the program's sys block
+  --disable <rules>     Don't run the given rule blocks, separated by commas
+  --order <rules>       Run the given rule blocks first, in the given order
+  --trace               Write how many cells each rule fired on, to stderr or among text grids
+  --write               With fmt, rewrite the file instead of printing it
+  -h, --help            Show this message
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Check,
+    Fmt,
+    DumpTokens,
+    DumpAst,
+    Export,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RendererKind {
+    Terminal,
+    Text,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub file: String,
+    pub tick: Option<Duration>,
+    pub generations: Option<usize>,
+    pub seed: Option<u64>,
+    pub renderer: RendererKind,
+    pub every: bool,
+    pub out: Option<String>,
+    pub print_out: Option<String>,
+    pub update_mode: UpdateMode,
+    pub constants: Vec<(String, String)>,
+    pub settings: Vec<(String, String)>,
+    pub disabled: Vec<String>,
+    pub order: Vec<String>,
+    pub trace: bool,
+    pub write: bool,
+}
+
+/// Why the command line couldn't be understood.
+#[derive(Debug)]
+pub enum CliError {
+    /// `--help` was given, so usage should be shown without an error.
+    Help,
+    Usage(String),
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Help => write!(f, "{USAGE}"),
+            CliError::Usage(msg) => write!(f, "error: {msg}\n\n{USAGE}"),
+        }
+    }
+}
+
+impl Options {
+    /// Parses the arguments after the program name. Options can be given as
+    /// either `--name value` or `--name=value`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, CliError> {
+        let mut args = args.into_iter().peekable();
+
+        let command = match args.peek().map(String::as_str) {
+            Some("run") => Some(Command::Run),
+            Some("check") => Some(Command::Check),
+            Some("fmt") => Some(Command::Fmt),
+            Some("dump-tokens") => Some(Command::DumpTokens),
+            Some("dump-ast") => Some(Command::DumpAst),
+            Some("export") => Some(Command::Export),
+            _ => None,
+        };
+        if command.is_some() {
+            args.next();
+        }
+
+        let mut options = Options {
+            command: command.unwrap_or(Command::Run),
+            file: String::new(),
+            tick: None,
+            generations: None,
+            seed: None,
+            renderer: RendererKind::Terminal,
+            every: false,
+            out: None,
+            print_out: None,
+            update_mode: UpdateMode::Synchronous,
+            constants: Vec::new(),
+            settings: Vec::new(),
+            disabled: Vec::new(),
+            order: Vec::new(),
+            trace: false,
+            write: false,
+        };
+
+        let mut file = None;
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                if let Some(first) = file.replace(arg.clone()) {
+                    return usage(format!("Expected one file, found {first} and {arg}"));
+                }
+                continue;
+            }
+
+            let (name, mut inline) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            let mut value = || match inline.take().or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => usage(format!("{name} expects a value")),
+            };
+
+            match name.as_str() {
+                "-h" | "--help" => return Err(CliError::Help),
+                "--tick" => options.tick = Some(Duration::from_millis(number(&name, value()?)?)),
+                "--generations" => options.generations = Some(number(&name, value()?)?),
+                "--seed" => options.seed = Some(number(&name, value()?)?),
+                "--renderer" => {
+                    options.renderer = match value()?.as_str() {
+                        "terminal" => RendererKind::Terminal,
+                        "text" => RendererKind::Text,
+                        other => {
+                            return usage(format!("Unknown renderer {other}, expected terminal or text"))
+                        }
+                    }
+                }
+                "--headless" => {
+                    options.renderer = RendererKind::Text;
+                    if let Some(generations) = inline.take() {
+                        options.generations = Some(number(&name, generations)?);
+                    }
+                }
+                "--every" => options.every = true,
+                "--out" => options.out = Some(value()?),
+                "--print-out" => options.print_out = Some(value()?),
+                "--in-place" => options.update_mode = UpdateMode::InPlace,
+                "--set" | "--sys" => match value()?.split_once('=') {
+                    Some((key, value)) => {
+                        let list = if name == "--set" { &mut options.constants } else { &mut options.settings };
+                        list.push((key.to_string(), value.to_string()))
+                    }
+                    None => return usage(format!("{name} expects a value like name=value")),
+                },
+                "--disable" => options.disabled.extend(names(value()?)),
+                "--order" => options.order = names(value()?),
+                "--trace" => options.trace = true,
+                "--write" => options.write = true,
+                _ => return usage(format!("Unknown option {name}")),
+            }
+
+            if inline.is_some() {
+                return usage(format!("{name} doesn't take a value"));
+            }
+        }
+
+        match file {
+            Some(file) => options.file = file,
+            None => return usage("No file supplied. Please specify a file to run.".to_string()),
+        }
+        Ok(options)
+    }
+}
+
+fn usage<T>(msg: String) -> Result<T, CliError> {
+    Err(CliError::Usage(msg))
+}
+
+/// Splits a comma separated list of names.
+fn names(value: String) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect()
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: String) -> Result<T, CliError> {
+    match value.parse() {
+        Ok(number) => Ok(number),
+        Err(_) => usage(format!("{name} expects a whole number, found {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, CliError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Parses arguments that should be rejected, returning why.
+    fn usage_error(args: &[&str]) -> String {
+        match parse(args) {
+            Err(CliError::Usage(msg)) => msg,
+            other => panic!("expected a usage error, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn options() {
+        let options = parse(&["export", "--tick=5", "--seed", "3", "--set", "v=(1, 2)", "a.cell"]).unwrap();
+        assert_eq!(options.command, Command::Export);
+        assert_eq!(options.file, "a.cell");
+        assert_eq!(options.tick, Some(Duration::from_millis(5)));
+        assert_eq!(options.seed, Some(3));
+        assert_eq!(options.constants, [("v".to_string(), "(1, 2)".to_string())]);
+
+        let options = parse(&["a.cell", "--headless=4", "--disable", "a, b", "--disable=c"]).unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert_eq!(options.renderer, RendererKind::Text);
+        assert_eq!(options.generations, Some(4));
+        assert_eq!(options.disabled, ["a", "b", "c"]);
+        assert!(matches!(parse(&["--help"]), Err(CliError::Help)));
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(usage_error(&["--nope", "a.cell"]), "Unknown option --nope");
+        assert_eq!(usage_error(&["a.cell", "--tick"]), "--tick expects a value");
+        assert_eq!(usage_error(&["--tick", "soon", "a.cell"]), "--tick expects a whole number, found soon");
+        assert_eq!(usage_error(&["--every=yes", "a.cell"]), "--every doesn't take a value");
+        assert_eq!(usage_error(&["--set", "v", "a.cell"]), "--set expects a value like name=value");
+        assert_eq!(usage_error(&["--renderer=gui", "a.cell"]), "Unknown renderer gui, expected terminal or text");
+        assert_eq!(usage_error(&["a.cell", "b.cell"]), "Expected one file, found a.cell and b.cell");
+        assert_eq!(usage_error(&["check"]), "No file supplied. Please specify a file to run.");
+    }
+}
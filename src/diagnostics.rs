@@ -0,0 +1,207 @@
+use std::io::{stderr, IsTerminal};
+
+use crate::lexer::{Error, Location};
+use crate::positioned::Position;
+
+const RED: &str = "\x1b[1;91m";
+const BLUE: &str = "\x1b[1;94m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A named piece of source code that errors can point into.
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+/// Renders errors against the sources they came from, with the lines they
+/// point at underlined.
+///
+/// The primary span of an error is underlined with `^`, and any secondary
+/// labels with `-` followed by their message. Spans over several lines are
+/// underlined on each of them.
+pub struct Diagnostics {
+    sources: Vec<Source>,
+    colour: bool,
+}
+
+impl Diagnostics {
+    /// Creates diagnostics that are coloured only when stderr is a terminal.
+    pub fn new() -> Self {
+        Self { sources: Vec::new(), colour: stderr().is_terminal() }
+    }
+
+    /// Adds a source, returning the number errors in it should use as their
+    /// `file`.
+    pub fn add(&mut self, name: &str, text: &str) -> usize {
+        self.sources.push(Source { name: name.to_string(), text: text.to_string() });
+        self.sources.len() - 1
+    }
+
+    /// Writes every error to stderr, followed by a count if there's more than
+    /// one.
+    pub fn emit(&self, errors: &[Error]) {
+        for error in errors {
+            eprintln!("{}", self.render(error));
+        }
+        if errors.len() > 1 {
+            eprintln!("{}: found {} errors", self.paint(RED, "error"), errors.len());
+        }
+    }
+
+    pub fn render(&self, error: &Error) -> String {
+        let mut out = format!("{}{}\n", self.paint(RED, "error"), self.paint(BOLD, &format!(": {}", error.msg)));
+        let source = self.sources.get(error.file);
+
+        let mut spans = Vec::new();
+        if !error.start.end {
+            spans.push((error.start, error.end, '^', RED, ""));
+        }
+        for label in &error.details.labels {
+            spans.push((label.start, label.end, '-', BLUE, label.msg.as_str()));
+        }
+
+        let lines: Vec<&str> = source.map_or(Vec::new(), |source| source.text.lines().collect());
+        let mut shown: Vec<usize> = spans
+            .iter()
+            .flat_map(|(start, end, ..)| start.line..=end.line.max(start.line))
+            .filter(|line| *line >= 1 && *line <= lines.len())
+            .collect();
+        shown.sort_unstable();
+        shown.dedup();
+
+        let width = shown.last().map_or(1, |line| line.to_string().len());
+        let gutter = " ".repeat(width);
+        let bar = self.paint(BLUE, "|");
+
+        if let Some(source) = source {
+            match error.start.end {
+                true => out.push_str(&format!("{gutter}{} {}\n", self.paint(BLUE, "-->"), source.name)),
+                false => out.push_str(&format!(
+                    "{gutter}{} {}:{}:{}\n",
+                    self.paint(BLUE, "-->"),
+                    source.name,
+                    error.start.line,
+                    error.start.col.saturating_sub(1).max(1)
+                )),
+            }
+        }
+
+        if !shown.is_empty() {
+            out.push_str(&format!("{gutter} {bar}\n"));
+        }
+        let mut previous = None;
+        for line in shown {
+            if previous.is_some_and(|previous| line > previous + 1) {
+                out.push_str(&format!("{}\n", self.paint(BLUE, "...")));
+            }
+            previous = Some(line);
+
+            let text = lines[line - 1];
+            out.push_str(&format!("{} {bar} {text}\n", self.paint(BLUE, &format!("{line:>width$}"))));
+            for (start, end, mark, colour, msg) in &spans {
+                if let Some((from, to)) = Self::underline(text, line, *start, *end) {
+                    let marks = mark.to_string().repeat(to - from);
+                    let label = match line == end.line.max(start.line) && !msg.is_empty() {
+                        true => format!("{marks} {msg}"),
+                        false => marks,
+                    };
+                    out.push_str(&format!("{gutter} {bar} {}{}\n", " ".repeat(from), self.paint(colour, &label)));
+                }
+            }
+        }
+
+        if let Some(Location { x, y, generation }) = error.details.location {
+            let note = format!("while computing cell ({x}, {y}) in generation {generation}");
+            out.push_str(&format!("{gutter} {} note: {note}\n", self.paint(BLUE, "=")));
+        }
+        for note in &error.details.notes {
+            out.push_str(&format!("{gutter} {} note: {note}\n", self.paint(BLUE, "=")));
+        }
+        if let Some(help) = &error.details.help {
+            out.push_str(&format!("{gutter} {} help: {help}\n", self.paint(BLUE, "=")));
+        }
+        out
+    }
+
+    /// The columns of `text`, line number `line`, covered by a span, if any.
+    /// Positions are one column past the character they point at, and the
+    /// first and last lines of a span are only covered from its start and up
+    /// to its end.
+    fn underline(text: &str, line: usize, start: Position, end: Position) -> Option<(usize, usize)> {
+        let end_line = end.line.max(start.line);
+        if line < start.line || line > end_line {
+            return None;
+        }
+
+        let len = text.chars().count();
+        let indent = text.chars().take_while(|c| c.is_whitespace()).count();
+        let from = match line == start.line {
+            true => start.col.saturating_sub(2),
+            false => indent,
+        };
+        let to = match line == end_line {
+            true => end.col.saturating_sub(1),
+            false => len,
+        };
+        Some((from.min(len), to.max(from + 1)))
+    }
+
+    fn paint(&self, colour: &str, text: &str) -> String {
+        match self.colour {
+            true => format!("{colour}{text}{RESET}"),
+            false => text.to_string(),
+        }
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(text: &str, error: &Error) -> String {
+        let mut diagnostics = Diagnostics { sources: Vec::new(), colour: false };
+        diagnostics.add("test.cell", text);
+        diagnostics.render(error)
+    }
+
+    fn position(line: usize, col: usize) -> Position {
+        Position { line, col, end: false }
+    }
+
+    #[test]
+    fn error_at_first_column() {
+        let error = Error::new("Unexpected x", position(1, 2), position(1, 2));
+        let expected = "error: Unexpected x\n --> test.cell:1:1\n  |\n1 | x\n  | ^\n";
+        assert_eq!(render("x", &error), expected);
+    }
+
+    #[test]
+    fn span_over_several_lines() {
+        let error = Error::new("Bad", position(1, 4), position(2, 5));
+        let expected = "error: Bad\n --> test.cell:1:3\n  |\n1 | a b c\n  |   ^^^\n2 |   de f\n  |   ^^\n";
+        assert_eq!(render("a b c\n  de f", &error), expected);
+    }
+
+    #[test]
+    fn labels_notes_and_help() {
+        let error = Error::new("Bad", position(3, 2), position(3, 3))
+            .with_label("first", position(1, 2), position(1, 2))
+            .with_note("a note")
+            .with_help("some help");
+        let expected = "error: Bad\n --> test.cell:3:1\n  |\n1 | a\n  | - first\n...\n3 | bc\n  | ^^\n  = note: a note\n  = help: some help\n";
+        assert_eq!(render("a\n\nbc", &error), expected);
+    }
+
+    #[test]
+    fn error_without_position() {
+        let error = Error::new("No memory found", Position::end(), Position::end());
+        assert_eq!(render("", &error), "error: No memory found\n --> test.cell\n");
+    }
+}
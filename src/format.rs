@@ -0,0 +1,206 @@
+use crate::lexer::{Keyword, Token};
+use crate::positioned::{Position, Positioned};
+
+/// Rewrites a program with one space between tokens where it reads best and
+/// none where it doesn't, keeping its comments, line breaks and the text of
+/// every token as written. Runs of blank lines become a single blank line,
+/// and lines inside brackets, braces and memory blocks are indented.
+pub fn format(source: &str, tokens: &[Positioned<Token>]) -> String {
+    let lines: Vec<Vec<char>> = source.lines().map(|line| line.chars().collect()).collect();
+    let mut tokens = tokens.iter().filter(|token| !token.is_eof()).peekable();
+    let mut spacing = Spacing::default();
+    let mut formatted = Vec::new();
+
+    let mut line = 1;
+    while line <= lines.len() {
+        let depth = spacing.depth;
+        let (mut text, mut end, mut dedent) = (String::new(), None, false);
+        while let Some(token) = tokens.next_if(|token| token.start.line == line) {
+            if end.is_none() {
+                dedent = spacing.closes(&token.inner);
+            } else if spacing.space_before(&token.inner) {
+                text.push(' ');
+            }
+            text.push_str(&source_text(&lines, token.start, token.end));
+            spacing.push(&token.inner);
+            line = token.end.line;
+            end = Some(token.end);
+        }
+
+        // Comments run to the end of the line, so one can only follow the
+        // last token on it.
+        let rest = match end {
+            Some(end) => &lines[line - 1][end.col - 1..],
+            None => &lines[line - 1][..],
+        };
+        let rest = rest.iter().collect::<String>();
+        if let Some(comment) = rest.find("//").map(|start| rest[start..].trim_end()) {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(comment);
+        }
+
+        if !text.is_empty() {
+            let depth = if dedent { depth.saturating_sub(1) } else { depth };
+            text.insert_str(0, &"    ".repeat(depth));
+        }
+        formatted.push(text);
+        line += 1;
+    }
+
+    let mut output = String::new();
+    let mut blank = false;
+    for line in formatted {
+        if line.is_empty() {
+            blank = !output.is_empty();
+            continue;
+        }
+        if blank {
+            output.push('\n');
+            blank = false;
+        }
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+/// The text of the source between two positions. A token's start column is
+/// one past the column of its first character, and its end column is one
+/// past its last.
+fn source_text(lines: &[Vec<char>], start: Position, end: Position) -> String {
+    if start.line == end.line {
+        return lines[start.line - 1][start.col - 2..end.col - 1].iter().collect();
+    }
+
+    let mut text: String = lines[start.line - 1][start.col - 2..].iter().collect();
+    for line in &lines[start.line..end.line - 1] {
+        text.push('\n');
+        text.extend(line);
+    }
+    text.push('\n');
+    text.extend(&lines[end.line - 1][..end.col - 1]);
+    text
+}
+
+/// Tracks enough of the tokens seen so far to decide the spacing of the next.
+#[derive(Default)]
+struct Spacing<'a> {
+    previous: Option<&'a Token>,
+    before_previous: Option<&'a Token>,
+    in_memory: bool,
+    depth: usize,
+}
+
+impl<'a> Spacing<'a> {
+    fn push(&mut self, token: &'a Token) {
+        if self.closes(token) {
+            self.depth = self.depth.saturating_sub(1);
+        } else if Self::opens(token) || token.is_tilde() {
+            self.depth += 1;
+        }
+        if token.is_tilde() {
+            self.in_memory = !self.in_memory;
+        }
+        self.before_previous = self.previous;
+        self.previous = Some(token);
+    }
+
+    fn opens(token: &Token) -> bool {
+        matches!(token, Token::OpenParen | Token::OpenBracket | Token::OpenBrace)
+    }
+
+    fn closes(&self, token: &Token) -> bool {
+        matches!(token, Token::CloseParen | Token::CloseBracket | Token::CloseBrace)
+            || (token.is_tilde() && self.in_memory)
+    }
+
+    fn space_before(&self, next: &Token) -> bool {
+        let Some(previous) = self.previous else { return false };
+
+        if self.in_memory && (previous.is_tilde() || next.is_tilde()) {
+            return false;
+        }
+        if self.in_memory && (previous.is_pipe() || next.is_pipe()) {
+            return false;
+        }
+        if matches!(
+            next,
+            Token::Comma | Token::CloseParen | Token::CloseBracket | Token::Semicolon | Token::Period | Token::Colon
+        ) {
+            return false;
+        }
+        if matches!(
+            previous,
+            Token::OpenParen | Token::OpenBracket | Token::At | Token::Hash | Token::Period | Token::Semicolon | Token::Not
+        ) {
+            return false;
+        }
+
+        // The target of a statement, `|layer>`.
+        if previous.is_pipe() || (next.is_greater() && self.before_previous.is_some_and(Token::is_pipe)) {
+            return false;
+        }
+        if next.is_open_paren() && matches!(previous, Token::Identifier(_) | Token::Keyword(Keyword::Halt)) {
+            return false;
+        }
+        !self.is_prefix()
+    }
+
+    /// Whether the previous token is a prefix, a negative sign or the `=` of
+    /// a sum, rather than something between two values.
+    fn is_prefix(&self) -> bool {
+        let ends_value = |token: &Token| {
+            matches!(
+                token,
+                Token::Literal(_)
+                    | Token::Identifier(_)
+                    | Token::CloseParen
+                    | Token::CloseBracket
+                    | Token::Underscore
+                    | Token::Keyword(Keyword::Halt)
+            )
+        };
+        matches!(self.previous, Some(Token::Minus | Token::Define))
+            && !self.before_previous.is_some_and(ends_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn format_source(source: &str) -> String {
+        format(source, &Lexer::new(source.to_string()).scan_tokens().unwrap())
+    }
+
+    #[test]
+    fn spacing() {
+        assert_eq!(
+            format_source("_:@n==1&&#[@all>0]>=2|>-1+@(1,-2)"),
+            "_: @n == 1 && #[@all > 0] >= 2 |> -1 + @(1, -2)\n"
+        );
+        assert_eq!(format_source("~  a;2 |a ;2~\nrule r{\n0|heat> =[@all]\n}"), "~a;2|a;2~\nrule r {\n    0 |heat> =[@all]\n}\n");
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        assert_eq!(format_source("!a=0   // zero\n\n\n\n// alone  \n!b=1\n"), "!a = 0 // zero\n\n// alone\n!b = 1\n");
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        for source in [
+            include_str!("../conways.cell"),
+            include_str!("../helloworld.cell"),
+            include_str!("../loop.cell"),
+            include_str!("../main.cell"),
+            include_str!("../new.cell"),
+        ] {
+            let formatted = format_source(source);
+            assert_eq!(format_source(&formatted), formatted);
+        }
+    }
+}
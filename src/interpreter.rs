@@ -1,130 +1,508 @@
-use core::time;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::io::Write;
-use std::io::stdout;
-use std::thread;
+use std::time::Duration;
 
-use crate::lexer::Error;
+use crate::lexer::{Error, Location};
 use crate::positioned::Position;
 use crate::positioned::Positioned;
+use crate::random::Random;
+use crate::render::{Colour, Renderer};
 use crate::value::Value;
 use crate::{
-    ast::Node,
+    ast::{Node, Shape, MAX_RADIUS, MAX_SIZE},
     lexer::{Token},
 };
 use fehler::throws;
 
 type PNode = Positioned<Node>;
+/// The parameters and body of a function, positioned at its definition.
+type Function = Positioned<(Vec<String>, Box<PNode>)>;
+pub type Grid = Vec<Vec<i64>>;
+
+/// A main statement ready to be matched against cells.
+struct MatchStatement {
+    centre: Box<PNode>,
+    conditional: Option<Box<PNode>>,
+    result: Box<PNode>,
+    print: bool,
+    /// The layer the statement matches and writes to.
+    layer: usize,
+    /// The rule block the statement is in, if any.
+    rule: Option<String>,
+}
+
+/// What a match statement does to a cell it matches.
+enum Effect {
+    Halt(u8),
+    Print(char),
+    /// A new value for the cell in a layer.
+    Write(usize, i64),
+}
+
+impl MatchStatement {
+    /// How the statement is referred to in traces, by its rule or its line.
+    fn name(&self) -> String {
+        match &self.rule {
+            Some(rule) => rule.clone(),
+            None => format!("line {}", self.centre.start.line),
+        }
+    }
+}
+
+/// The name of the layer unnamed memory statements fill.
+pub const MAIN_LAYER: &str = "main";
+
+/// Offsets of the eight cells surrounding the centre.
+const MOORE: [(isize, isize); 8] = [(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, 1), (1, -1), (-1, -1)];
+/// Offsets of the four cells sharing an edge with the centre.
+const DIRECT: [(isize, isize); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+/// Offsets of the four cells sharing only a corner with the centre.
+const DIAGONAL: [(isize, isize); 4] = [(-1, -1), (1, -1), (1, 1), (-1, 1)];
+
+/// Lists the offsets covered by a neighbourhood shape, row by row, leaving
+/// out the centre.
+pub fn shape_offsets(shape: &Shape) -> Vec<(isize, isize)> {
+    let (radius, within): (usize, fn(isize, isize, isize) -> bool) = match shape {
+        Shape::Offsets(offsets) => return offsets.clone(),
+        Shape::Moore(radius) => (*radius, |_, _, _| true),
+        Shape::VonNeumann(radius) => (*radius, |dx, dy, r| dx.abs() + dy.abs() <= r),
+        Shape::Hex(radius) => (*radius, |dx, dy, r| (dx.abs() + dy.abs() + (dx + dy).abs()) <= r * 2),
+    };
+
+    let r = radius as isize;
+    let mut offsets = Vec::new();
+    for dy in -r..=r {
+        for dx in -r..=r {
+            if (dx, dy) != (0, 0) && within(dx, dy, r) {
+                offsets.push((dx, dy));
+            }
+        }
+    }
+    offsets
+}
+
+/// How long the terminal waits between generations without a `tick` setting.
+pub const DEFAULT_TICK: Duration = Duration::from_millis(200);
+
+/// The deepest function calls are allowed to nest before evaluation gives up.
+/// A call can take over 10KB of stack in a debug build, so this stays well
+/// within the 2MB stacks threads get by default.
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// How a generation is written back to the grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpdateMode {
+    /// Every cell is computed from a snapshot of the previous generation,
+    /// and the new grid is swapped in once the scan is finished.
+    #[default]
+    Synchronous,
+    /// Results are written straight into the grid during the scan, so later
+    /// cells see the new values of earlier ones.
+    InPlace,
+}
+
+/// What the grid looks like beyond its edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    /// Every cell off the grid has the same value.
+    Constant(i64),
+    /// Both axes wrap around, so the grid is a torus.
+    Torus,
+    /// Only one axis wraps around.
+    Cylinder(Axis),
+    /// The grid is mirrored at its edges, so the cell just past an edge is
+    /// the cell on it.
+    Reflect,
+}
+
+impl Default for Boundary {
+    fn default() -> Self {
+        Boundary::Constant(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+}
+
+/// Why a program stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halt {
+    /// A generation left every cell as it was.
+    Stable,
+    /// The configured number of generations has run.
+    Limit,
+    /// A statement's result was `halt`, with the given exit code.
+    Rule(u8),
+}
+
+impl Halt {
+    pub fn code(&self) -> u8 {
+        match self {
+            Halt::Rule(code) => *code,
+            Halt::Stable | Halt::Limit => 0,
+        }
+    }
+}
 
 pub struct Interpreter {
     instructions: Vec<Positioned<Node>>,
     constants: HashMap<String, Value>,
-    functions: HashMap<String, (Vec<String>, Box<PNode>)>,
-    memory: Vec<Vec<u8>>,
-    match_statements: Vec<(Box<PNode>, Option<Box<PNode>>, Box<PNode>, bool)>,
-    index: usize,
+    /// Constants given a value from outside, and whether the program defines
+    /// each of them.
+    overridden: HashMap<String, bool>,
+    functions: HashMap<String, Function>,
+    neighbourhoods: HashMap<String, Vec<(isize, isize)>>,
+    scopes: RefCell<Vec<HashMap<String, Value>>>,
+    memory: Vec<Grid>,
+    layers: Vec<String>,
+    /// Where the memory statement that filled each layer is, to point errors
+    /// about its shape at.
+    layer_spans: Vec<Option<(Position, Position)>>,
+    current_layer: Cell<usize>,
+    match_statements: Vec<MatchStatement>,
+    /// The names of the rule blocks, in the order they were defined, each
+    /// positioned at its name.
+    rules: Vec<Positioned<String>>,
+    /// How many cells each match statement fired on this generation, when
+    /// tracing.
+    fired: Option<Vec<usize>>,
     current_x: usize,
     current_y: usize,
-    out: usize,
+    generation: usize,
+    output: String,
+    update_mode: UpdateMode,
+    boundary: Boundary,
+    generation_limit: Option<usize>,
+    /// Whether to halt on a stable generation. By default, `None`, this is
+    /// only done when the generation didn't read `@gen` or `@random`.
+    halt_when_stable: Option<bool>,
+    /// Whether the current generation read `@gen` or `@random`, so could
+    /// change the grid later even when it didn't this time.
+    read_time: Cell<bool>,
+    halted: Option<u8>,
+    random: RefCell<Random>,
+    /// Whether the seed was given from outside, so a `seed` setting is
+    /// ignored.
+    fixed_seed: bool,
+    tick: Duration,
+    /// The width and height every layer is cropped or padded to.
+    size: Option<(usize, usize)>,
+    /// Colours for the cell values from 0 up, overriding the default ones.
+    palette: Vec<Colour>,
 }
 
 impl Interpreter {
     pub fn new(instructions: Vec<Positioned<Node>>) -> Self {
         Self { 
             instructions, 
-            index: 0, 
             constants: HashMap::new(), 
+            overridden: HashMap::new(),
             functions: HashMap::new(),
+            neighbourhoods: HashMap::new(),
+            scopes: RefCell::new(Vec::new()),
             memory: Vec::new(),
+            layers: Vec::new(),
+            layer_spans: Vec::new(),
+            current_layer: Cell::new(0),
             match_statements: Vec::new(),
+            rules: Vec::new(),
+            fired: None,
             current_x: 0,
             current_y: 0,
-            out: 0,
+            generation: 0,
+            output: String::new(),
+            update_mode: UpdateMode::default(),
+            boundary: Boundary::default(),
+            generation_limit: None,
+            halt_when_stable: None,
+            read_time: Cell::new(false),
+            halted: None,
+            random: RefCell::new(Random::from_time()),
+            fixed_seed: false,
+            tick: DEFAULT_TICK,
+            size: None,
+            palette: Vec::new(),
         }
     }
 
-    pub fn interpret(&mut self) -> Result<!, Error> {
-        self.load_instructions()?;
-        self.init_screen();
-        loop {
-            self.draw_screen();
-            self.match_cells()?;
-            print!("\r\x1b[{}C", self.out + 1);
-            stdout().flush().unwrap();
-            thread::sleep(time::Duration::from_millis(200));
+    pub fn with_update_mode(mut self, update_mode: UpdateMode) -> Self {
+        self.update_mode = update_mode;
+        self
+    }
+
+    /// Seeds `@random`, ignoring any `seed` setting in the program.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random = RefCell::new(Random::new(seed));
+        self.fixed_seed = true;
+        self
+    }
+
+    /// Gives a constant a value that the program's own definition of it
+    /// won't replace.
+    pub fn override_constant(&mut self, name: String, value: Value) {
+        self.constants.insert(name.clone(), value);
+        self.overridden.insert(name, false);
+    }
+
+    /// Checks that the program defines a constant given a value from
+    /// outside, since a misspelt name would otherwise do nothing.
+    #[throws]
+    pub fn check_override(&self, name: &str) {
+        if self.overridden.get(name) == Some(&false) {
+            let defined = self.constants.keys().filter(|name| self.overridden.get(*name) != Some(&false));
+            let mut defined = defined.cloned().collect::<Vec<_>>();
+            defined.sort();
+            Err(Self::raw_error(&format!("The program has no constant named {name}"))
+                .with_help(&format!("the constants are {}", defined.join(", "))))?;
+        }
+    }
+
+    /// Writes which statements fired after each generation to stderr.
+    pub fn with_trace(mut self) -> Self {
+        self.fired = Some(Vec::new());
+        self
+    }
+
+    /// Stops the statements of a rule block from being matched.
+    #[throws]
+    pub fn disable_rule(&mut self, name: &str) {
+        self.check_rule(name)?;
+        self.match_statements.retain(|statement| statement.rule.as_deref() != Some(name));
+    }
+
+    /// Matches the statements of the given rule blocks first, in the given
+    /// order, followed by every other statement in the order it was written.
+    #[throws]
+    pub fn order_rules(&mut self, order: &[String]) {
+        for name in order {
+            self.check_rule(name)?;
         }
+        self.match_statements.sort_by_key(|statement| {
+            let rule = statement.rule.as_ref();
+            order.iter().position(|name| Some(name) == rule).unwrap_or(order.len())
+        });
     }
 
-    pub fn init_screen(&mut self) {
-        let height = (self.memory.len() as f32 / 2.0).round() as usize;
-        let width = self.memory[0].len();
-        for _ in 0..height {
-            println!(" {}", "▒".repeat(width));
+    /// Makes sure a rule block with the given name exists.
+    #[throws]
+    fn check_rule(&self, name: &str) {
+        if self.rules.iter().any(|rule| rule.inner == name) {
+            return;
         }
+        let rules = self.rules.iter().map(|rule| rule.inner.as_str()).collect::<Vec<_>>();
+        let help = match rules.is_empty() {
+            true => "the program has no rule blocks".to_string(),
+            false => format!("the rules are {}", rules.join(", ")),
+        };
+        Err(Self::raw_error(&format!("No rule named {name}")).with_help(&help))?
+    }
+
+    pub fn set_generation_limit(&mut self, limit: usize) {
+        self.generation_limit = Some(limit);
     }
 
-    pub fn draw_screen(&mut self) {
-        let height = (self.memory.len() as f32 / 2.0).round() as usize;
-        let width = self.memory[0].len();
+    pub fn generation_limit(&self) -> Option<usize> {
+        self.generation_limit
+    }
 
-        println!("\x1b[{}A\r", height + 1);
-        for y in 0..height {
-            print!("\x1b[0m ");
-            for x in 0..width {
-                let fg = self.get_cell(x, y * 2);
-                let bg = self.get_cell(x, y * 2 + 1);
-                
-                print!("{}{}▀", 
-                       self.ansi_colour(fg, true), 
-                       self.ansi_colour(bg, false));
+    pub fn set_tick(&mut self, tick: Duration) {
+        self.tick = tick;
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    pub fn palette(&self) -> &[Colour] {
+        &self.palette
+    }
+
+    /// Runs the loaded program until it halts, showing each generation with
+    /// the given renderer.
+    pub fn run(&mut self, renderer: &mut dyn Renderer) -> Result<Halt, Error> {
+        renderer.start(self)?;
+        let halt = loop {
+            // A limit of zero generations, or one already reached, runs none.
+            if self.generation_limit.is_some_and(|limit| self.generation >= limit) {
+                break Halt::Limit;
             }
-            println!("\x1b[0m");
+
+            let changed = self.match_cells()?;
+            if let Some(trace) = self.trace() {
+                renderer.trace(&trace)?;
+            }
+            let output = std::mem::take(&mut self.output);
+            renderer.generation(self, &output)?;
+
+            if let Some(halt) = self.halt(changed || !output.is_empty()) {
+                break halt;
+            }
+        };
+        renderer.finish(self)?;
+        Ok(halt)
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn layers(&self) -> &[String] {
+        &self.layers
+    }
+
+    pub fn grid(&self, layer: usize) -> &Grid {
+        &self.memory[layer]
+    }
+
+    /// Lists how many cells each rule, or each statement outside of one,
+    /// fired on in the last generation, if tracing.
+    fn trace(&self) -> Option<String> {
+        let fired = self.fired.as_ref()?;
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        for (statement, count) in self.match_statements.iter().zip(fired) {
+            let name = statement.name();
+            match counts.iter_mut().find(|(counted, _)| *counted == name) {
+                Some((_, total)) => *total += count,
+                None => counts.push((name, *count)),
+            }
+        }
+        let counts = counts.iter().map(|(name, count)| format!("{name}={count}")).collect::<Vec<_>>();
+        Some(format!("generation {}: {}", self.generation, counts.join(", ")))
+    }
+
+    /// Decides whether to stop after a generation, given whether it changed
+    /// any cells.
+    pub fn halt(&self, changed: bool) -> Option<Halt> {
+        if let Some(code) = self.halted {
+            return Some(Halt::Rule(code));
+        }
+        if self.generation_limit.is_some_and(|limit| self.generation >= limit) {
+            return Some(Halt::Limit);
+        }
+        let halt_when_stable = self.halt_when_stable.unwrap_or(!self.read_time.get());
+        if halt_when_stable && !changed {
+            return Some(Halt::Stable);
         }
+        None
     }
 
+    /// Computes the next generation, returning whether any cell changed.
     #[throws]
-    pub fn match_cells(&mut self) {
-        for y in 0..self.memory.len() {
-            for x in 0..self.memory[y].len() {
+    pub fn match_cells(&mut self) -> bool {
+        let mut changed = false;
+        self.read_time.set(false);
+        let mut next = match self.update_mode {
+            UpdateMode::Synchronous => Some(self.memory.clone()),
+            UpdateMode::InPlace => None,
+        };
+        if let Some(fired) = &mut self.fired {
+            *fired = vec![0; self.match_statements.len()];
+        }
+
+        for y in 0..self.memory[0].len() {
+            for x in 0..self.memory[0][y].len() {
                 self.current_y = y;
                 self.current_x = x;
-                self.match_cell(x, y)?;
+                let location = Location { x, y, generation: self.generation };
+                let results = self
+                    .match_cell(x, y)
+                    .map_err(|mut err| {
+                        err.details.location = Some(location);
+                        err
+                    })?;
+                for (layer, value) in results {
+                    changed |= self.memory[layer][y][x] != value;
+                    match &mut next {
+                        Some(next) => next[layer][y][x] = value,
+                        None => self.memory[layer][y][x] = value,
+                    }
+                }
             }
         }
+
+        if let Some(next) = next {
+            self.memory = next;
+        }
+        self.generation += 1;
+        changed
     }
 
+    /// Runs the match statements against a single cell, collecting any output
+    /// and returning the new value of each layer the statements changed.
     #[throws]
-    pub fn match_cell(&mut self, x: usize, y: usize,) {
-        let (mut has_printed, mut has_changed) = (false, false);
-        for (centre, body, result, print) in &self.match_statements {
-            if has_printed && *print { continue; }
-            if has_changed && !print { continue; }
-
-            let c_eval = self.evaluate(centre)?;
-            if c_eval != Value::Unknown && self.memory[y][x] != c_eval.as_num() { continue; }
-
-            if let Some(b) = body {
-                if !self.evaluate(b)?.as_bool() { continue; }
-            } 
+    pub fn match_cell(&mut self, x: usize, y: usize) -> Vec<(usize, i64)> {
+        let (mut has_printed, mut changed) = (false, Vec::new());
+        for (index, statement) in self.match_statements.iter().enumerate() {
+            // Halts never claim a cell, so they run whichever statements
+            // have written or printed from it.
+            let halts = matches!(statement.result.inner, Node::Halt(_));
+            if has_printed && statement.print && !halts { continue; }
+            if !statement.print && !halts && changed.iter().any(|(changed, _)| *changed == statement.layer) {
+                continue;
+            }
 
-            let result = self.evaluate(result)?;
-            if *print {
-                print!("\r\x1b[{}C{}", self.out + 1, result.as_char());
-                self.out += 1;
-                has_printed = true;
-            } else {
-                self.memory[y][x] = result.as_num();
-                has_changed = true;
+            let effect = self.match_statement(statement, x, y).map_err(|err| match &statement.rule {
+                Some(rule) => err.with_note(&format!("in rule {rule}")),
+                None => err,
+            })?;
+            let Some(effect) = effect else { continue };
+            match effect {
+                Effect::Halt(code) => {
+                    self.halted.get_or_insert(code);
+                }
+                Effect::Print(c) => {
+                    self.output.push(c);
+                    has_printed = true;
+                }
+                Effect::Write(layer, value) => changed.push((layer, value)),
+            }
+            if let Some(fired) = &mut self.fired {
+                fired[index] += 1;
             }
         }
+        changed
     }
 
-    pub fn ansi_colour(&self, v: u8, fg: bool) -> String {
-        format!("\x1b[{};2;{v};{v};{v}m", if fg { "38" } else { "48" })
+    /// Runs a single match statement against a cell, returning what it does
+    /// if it matches.
+    #[throws]
+    fn match_statement(&self, statement: &MatchStatement, x: usize, y: usize) -> Option<Effect> {
+        let MatchStatement { centre, conditional, result, print, layer, .. } = statement;
+        self.current_layer.set(*layer);
+        let c_eval = self.evaluate(centre)?;
+        if c_eval != Value::Unknown
+            && self.memory[*layer][y][x] != Self::number(c_eval, centre, "to match cells against")?
+        {
+            return None;
+        }
+
+        if let Some(b) = conditional {
+            if !self.evaluate(b)?.as_bool() { return None; }
+        }
+
+        if let Node::Halt(code) = &result.inner {
+            let code = match code {
+                Some(code) => {
+                    let code = Self::number(self.evaluate(code)?, code, "as an exit code")?;
+                    code.clamp(0, u8::MAX as i64) as u8
+                }
+                None => 0,
+            };
+            return Some(Effect::Halt(code));
+        }
+
+        let value = self.evaluate(result)?;
+        match print {
+            true => Some(Effect::Print(value.as_char())),
+            false => Some(Effect::Write(*layer, Self::number(value, result, "to store in a cell")?)),
+        }
     }
 
-    pub fn get_cell(&self, x: usize, y: usize) -> u8 {
-        let line = match self.memory.get(y) {
+    pub fn get_cell(&self, x: usize, y: usize) -> i64 {
+        let line = match self.memory[0].get(y) {
             Some(line) => line,
             None => return 0,
         };
@@ -132,127 +510,718 @@ impl Interpreter {
         *line.get(x).unwrap_or(&0)
     }
 
-    pub fn get_cell_signed(&self, x: isize, y: isize) -> u8 {
-        if x < 0 { return 0; }
-        if y < 0 { return 0; }
+    /// Reads a cell of a layer that may be off the grid, following the
+    /// boundary setting.
+    pub fn get_cell_signed(&self, layer: usize, x: isize, y: isize) -> i64 {
+        let memory = &self.memory[layer];
+        let (wrap_x, wrap_y) = match self.boundary {
+            Boundary::Constant(_) => (false, false),
+            Boundary::Torus | Boundary::Reflect => (true, true),
+            Boundary::Cylinder(Axis::X) => (true, false),
+            Boundary::Cylinder(Axis::Y) => (false, true),
+        };
 
-        let line = match self.memory.get(y as usize) {
+        let y = if wrap_y { self.fold(y, memory.len()) } else { y };
+        let line = match usize::try_from(y).ok().and_then(|y| memory.get(y)) {
             Some(line) => line,
-            None => return 0,
+            None => return self.outside(),
         };
 
-        *line.get(x as usize).unwrap_or(&0)
+        let x = if wrap_x { self.fold(x, line.len()) } else { x };
+        match usize::try_from(x).ok().and_then(|x| line.get(x)) {
+            Some(cell) => *cell,
+            None => self.outside(),
+        }
+    }
+
+    /// Brings a coordinate back onto an axis of the given length by wrapping
+    /// or mirroring it.
+    fn fold(&self, i: isize, len: usize) -> isize {
+        let len = len as isize;
+        if len == 0 {
+            return i;
+        }
+        if self.boundary != Boundary::Reflect {
+            return i.rem_euclid(len);
+        }
+
+        let folded = i.rem_euclid(len * 2);
+        if folded >= len { len * 2 - folded - 1 } else { folded }
+    }
+
+    fn outside(&self) -> i64 {
+        match self.boundary {
+            Boundary::Constant(value) => value,
+            _ => 0,
+        }
+    }
+
+    /// Reads the cell of a layer at an offset from the one being matched.
+    /// Offsets too large to add are clamped, which is still far off the grid.
+    pub fn relative(&self, layer: usize, dx: isize, dy: isize) -> Value {
+        let (x, y) = (self.current_x as isize, self.current_y as isize);
+        Value::Int(self.get_cell_signed(layer, x.saturating_add(dx), y.saturating_add(dy)))
+    }
+
+    /// Finds a layer by name, defaulting to the layer of the statement being
+    /// matched.
+    #[throws]
+    pub fn layer_index(&self, layer: &Option<String>) -> usize {
+        match layer {
+            Some(name) => match self.layers.iter().position(|layer| layer == name) {
+                Some(index) => index,
+                None => Err(self.unknown_layer(name))?,
+            },
+            None => self.current_layer.get(),
+        }
     }
 
     #[throws]
     pub fn load_instructions(&mut self) {
-        for node in &self.instructions {
-            match node.inner.clone() {
-                Node::Definition { value, name } => {
-                    let value = self.evaluate(&value)?;
-                    self.constants.insert(name.to_string(), value);
+        let mut targets = Vec::new();
+        for node in self.instructions.clone() {
+            self.load_instruction(&node, &mut targets).map_err(|err| err.or_at(node.start, node.end))?;
+        }
+
+        if self.size.is_some() && self.memory.is_empty() {
+            self.layers.push(MAIN_LAYER.to_string());
+            self.layer_spans.push(None);
+            self.memory.push(Vec::new());
+        }
+        self.resize();
+        self.check_layers()?;
+        for (index, (target, start, end)) in targets.into_iter().enumerate() {
+            self.match_statements[index].layer = match target {
+                Some(name) => match self.layers.iter().position(|layer| *layer == name) {
+                    Some(layer) => layer,
+                    None => Err(self.unknown_layer(&name).or_at(start, end))?,
+                },
+                None => 0,
+            };
+        }
+    }
+
+    /// Loads a single top level statement, collecting the target layer of
+    /// main statements to resolve once every layer is known.
+    #[throws]
+    fn load_instruction(&mut self, node: &PNode, targets: &mut Vec<(Option<String>, Position, Position)>) {
+        match node.inner.clone() {
+            Node::Definition { value, name } => {
+                if let Some(defined) = self.overridden.get_mut(&name) {
+                    *defined = true;
+                    return;
                 }
-                Node::Function { name, params, body } => {
-                    self.functions.insert(name.to_string(), (params, body)); 
+                let value = self.evaluate(&value)?;
+                self.constants.insert(name.to_string(), value);
+            }
+            Node::Function { name, params, body } => {
+                let function = Positioned { inner: (params, body), start: node.start, end: node.end };
+                self.functions.insert(name.to_string(), function);
+            }
+            Node::Main { .. } => self.push_statement(node, None, targets),
+            Node::Rule { name, statements } => {
+                if let Some(rule) = self.rules.iter().find(|rule| rule.inner == name.inner) {
+                    let error = Error::new(&format!("There is already a rule named {}", name.inner), name.start, name.end)
+                        .with_label(&format!("{} is first defined here", rule.inner), rule.start, rule.end);
+                    Err(error)?;
                 }
-                Node::Main { centre, conditional, result, print } => {
-                    self.match_statements.push((centre, conditional, result, print));
+                for statement in &statements {
+                    self.push_statement(statement, Some(name.inner.clone()), targets);
                 }
-                Node::Memory(memory) => {
-                    self.memory = memory.into_iter().map(|r| r.into_iter().map(|item| self.constants[&item].clone().as_num()).collect()).collect();
+                self.rules.push(name);
+            }
+            Node::Neighbourhood { name, shape } => {
+                self.neighbourhoods.insert(name, shape_offsets(&shape));
+            }
+            Node::Sys(settings) => {
+                for (name, value) in settings {
+                    self.apply_setting(&name, &value).map_err(|err| err.or_at(value.start, value.end))?;
                 }
-                _ => {
-                    println!("{:?}", node);
-                    self.evaluate(&node)?;
+            }
+            Node::Memory { layer, rows } => {
+                let mut memory = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let mut cells = Vec::with_capacity(row.len());
+                    for item in row {
+                        let value = match self.constants.get(&item) {
+                            Some(value) => value.clone(),
+                            None => Self::error(&format!(
+                                "No constant named {item}, memory can only be filled with constants defined with !"
+                            ))?,
+                        };
+                        cells.push(Self::number(value, node, "to store in a cell")?);
+                    }
+                    memory.push(cells);
+                }
+                let name = layer.unwrap_or_else(|| MAIN_LAYER.to_string());
+                let span = Some((node.start, node.end));
+                match self.layers.iter().position(|layer| *layer == name) {
+                    Some(index) => {
+                        self.memory[index] = memory;
+                        self.layer_spans[index] = span;
+                    }
+                    None => {
+                        self.layers.push(name);
+                        self.layer_spans.push(span);
+                        self.memory.push(memory);
+                    }
                 }
+            }
+            _ => Err(Self::raw_error("Expected a statement").or_at(node.start, node.end))?,
+        };
+    }
+
+    /// Adds a main statement to be matched, leaving its target layer to be
+    /// resolved once every layer is known.
+    fn push_statement(
+        &mut self,
+        node: &PNode,
+        rule: Option<String>,
+        targets: &mut Vec<(Option<String>, Position, Position)>,
+    ) {
+        if let Node::Main { centre, conditional, result, print, target } = node.inner.clone() {
+            self.match_statements.push(MatchStatement { centre, conditional, result, print, layer: 0, rule });
+            targets.push((target, node.start, node.end));
+        }
+    }
+
+    fn unknown_layer(&self, name: &str) -> Error {
+        let help = format!("the layers are {}", self.layers.join(", "));
+        Self::raw_error(&format!("No layer named {name}")).with_help(&help)
+    }
+
+    /// Makes sure there is a grid, that its rows are all the same length,
+    /// and that every layer is the same shape as the first.
+    #[throws]
+    pub fn check_layers(&self) {
+        let Some(first) = self.memory.first() else {
+            Self::error("No memory found, expected a ~...~ memory statement")?
+        };
+        if first.first().is_none_or(|row| row.is_empty()) {
+            Err(self.layer_error(0, &format!("Layer {} has no cells", self.layers[0])))?;
+        }
+        let width = first[0].len();
+        if let Some(row) = first.iter().position(|row| row.len() != width) {
+            let msg = format!(
+                "Row {} of layer {} has {} cells, but its first row has {width}",
+                row + 1,
+                self.layers[0],
+                first[row].len()
+            );
+            Err(self.layer_error(0, &msg).with_help("every row of a grid must be the same length"))?;
+        }
+
+        let shape = |grid: &Grid| grid.iter().map(|row| row.len()).collect::<Vec<_>>();
+        for (index, grid) in self.memory.iter().enumerate().skip(1) {
+            if shape(grid) != shape(first) {
+                let msg = format!("Layer {} is a different shape to layer {}", self.layers[index], self.layers[0]);
+                let mut err = self.layer_error(index, &msg);
+                if let Some((start, end)) = self.layer_spans[0] {
+                    err = err.with_label(&format!("{} is filled here", self.layers[0]), start, end);
+                }
+                Err(err)?;
+            }
+        }
+    }
+
+    /// An error about the shape of a layer, pointing at the memory statement
+    /// that filled it.
+    fn layer_error(&self, layer: usize, msg: &str) -> Error {
+        match self.layer_spans[layer] {
+            Some((start, end)) => Error::new(msg, start, end),
+            None => Self::raw_error(msg),
+        }
+    }
+
+    #[throws]
+    pub fn apply_setting(&mut self, name: &str, value: &PNode) {
+        match name {
+            "boundary" => self.boundary = self.boundary_setting(value)?,
+            "generations" => self.generation_limit = Some(self.count_setting(value, "of generations")?),
+            "stable" => match &value.inner {
+                Node::Halt(None) => self.halt_when_stable = Some(true),
+                Node::Variable(action) if action == "continue" => self.halt_when_stable = Some(false),
+                _ => Self::error("Expected halt or continue for what to do when stable")?,
+            },
+            "tick" => self.tick = Duration::from_millis(self.count_setting(value, "of milliseconds per tick")? as u64),
+            "seed" => {
+                let seed = Self::number(self.evaluate(value)?, value, "as a seed")?;
+                if !self.fixed_seed {
+                    self.random = RefCell::new(Random::new(seed as u64));
+                }
+            }
+            "size" => {
+                let (width, height) = match &value.inner {
+                    Node::Tuple(size) if size.len() == 2 => (&size[0], &size[1]),
+                    _ => Err(Self::raw_error("Expected a size like (width, height)").with_help("eg size = (40, 20)"))?,
+                };
+                let size = (self.count_setting(width, "as a width")?, self.count_setting(height, "as a height")?);
+                if size.0 == 0 || size.1 == 0 {
+                    Self::error("The grid needs at least one cell")?;
+                }
+                for (length, node, side) in [(size.0, width, "wide"), (size.1, height, "high")] {
+                    if length > MAX_SIZE {
+                        Err(Self::raw_error(&format!("A grid can be at most {MAX_SIZE} cells {side}"))
+                            .or_at(node.start, node.end))?;
+                    }
+                }
+                self.size = Some(size);
+                self.resize();
+            }
+            "palette" => self.palette = self.palette_setting(value)?,
+            _ => Err(Self::raw_error(&format!("Unknown setting {name}"))
+                .with_help("the settings are boundary, generations, palette, seed, size, stable and tick"))?,
+        }
+    }
+
+    /// Reads a setting that counts something, so can't be negative.
+    #[throws]
+    fn count_setting(&self, value: &PNode, usage: &str) -> usize {
+        let count = Self::number(self.evaluate(value)?, value, usage)?;
+        match usize::try_from(count) {
+            Ok(count) => count,
+            Err(_) => Err(Self::raw_error(&format!("Expected a number that isn't negative {usage}, found {count}"))
+                .or_at(value.start, value.end))?,
+        }
+    }
+
+    /// Reads a palette setting, either `default` or an array of `[r, g, b]`
+    /// colours for the cell values from 0 up.
+    #[throws]
+    fn palette_setting(&self, value: &PNode) -> Vec<Colour> {
+        if matches!(&value.inner, Node::Variable(name) if name == "default") {
+            return Vec::new();
+        }
+
+        let help = "a palette is default, or an array of colours like [[0, 0, 0], [255, 128, 0]]";
+        let Value::Array(colours) = self.evaluate(value)? else {
+            Err(Self::raw_error("Expected an array of colours").with_help(help))?
+        };
+        let mut palette = Vec::with_capacity(colours.len());
+        for colour in colours {
+            let channels = match &colour {
+                Value::Array(channels) if channels.len() == 3 => channels,
+                _ => Err(Self::raw_error(&format!("Expected a colour like [r, g, b], found {colour}")).with_help(help))?,
             };
+            let mut rgb = [0; 3];
+            for (channel, value) in rgb.iter_mut().zip(channels) {
+                *channel = match value.as_num().and_then(|num| u8::try_from(num).ok()) {
+                    Some(channel) => channel,
+                    None => Err(Self::raw_error("Colour channels go from 0 to 255").with_help(help))?,
+                };
+            }
+            palette.push((rgb[0], rgb[1], rgb[2]));
         }
+        palette
     }
 
-    pub fn next(&mut self) -> Positioned<Node> {
-        self.index += 1;
-        self.instructions[self.index - 1].clone()
+    /// Crops or pads every layer with zeros to the size setting, if there is
+    /// one.
+    fn resize(&mut self) {
+        let Some((width, height)) = self.size else { return };
+        for grid in &mut self.memory {
+            grid.resize(height, Vec::new());
+            for row in grid.iter_mut() {
+                row.resize(width, 0);
+            }
+        }
+    }
+
+    /// Reads a boundary setting: `torus`, `reflect`, `cylinder` (wrapping
+    /// left to right), `cylinder(y)` (wrapping top to bottom), `constant(n)`
+    /// or just `n`.
+    #[throws]
+    pub fn boundary_setting(&self, value: &PNode) -> Boundary {
+        let (name, args) = match &value.inner {
+            Node::Variable(name) => (name.as_str(), &[][..]),
+            Node::Call { expr, args } => match &expr.inner {
+                Node::Variable(name) => (name.as_str(), &args[..]),
+                _ => Self::error("Expected a boundary")?,
+            },
+            _ => return Boundary::Constant(Self::number(self.evaluate(value)?, value, "as a boundary")?),
+        };
+
+        match (name, args) {
+            ("torus", []) => Boundary::Torus,
+            ("reflect", []) => Boundary::Reflect,
+            ("cylinder", []) => Boundary::Cylinder(Axis::X),
+            ("cylinder", [axis]) => match &axis.inner {
+                Node::Variable(axis) if axis == "x" => Boundary::Cylinder(Axis::X),
+                Node::Variable(axis) if axis == "y" => Boundary::Cylinder(Axis::Y),
+                _ => Self::error("Expected x or y as the axis of a cylinder")?,
+            },
+            ("constant", [constant]) => {
+                Boundary::Constant(Self::number(self.evaluate(constant)?, constant, "as a boundary")?)
+            }
+            _ => Self::error(&format!(
+                "Unknown boundary {name}, expected torus, cylinder, reflect or constant"
+            ))?,
+        }
+    }
+
+    /// Evaluates an expression. Errors that don't already point somewhere
+    /// more precise point at the expression.
+    pub fn evaluate(&self, value: &Positioned<Node>) -> Result<Value, Error> {
+        self.evaluate_node(value).map_err(|err| err.or_at(value.start, value.end))
     }
 
     #[throws]
-    pub fn evaluate(&self, value: &Positioned<Node>) -> Value {
+    fn evaluate_node(&self, value: &Positioned<Node>) -> Value {
         match &value.inner {
-            Node::Directional(direction) => self.get_direction(direction),
+            Node::Directional { layer, name, arg } => {
+                match self.lookup(&Self::binding_key(layer, name, arg)) {
+                    Some(bound) => bound,
+                    None => self.get_direction(self.layer_index(layer)?, name, arg.as_deref())?,
+                }
+            }
+            Node::Offset { layer, dx, dy } => {
+                self.relative(self.layer_index(layer)?, self.offset(dx)?, self.offset(dy)?)
+            }
+            Node::Unary { op, expr } => {
+                let value = self.evaluate(expr)?;
+                let result = match op {
+                    Token::Minus => value.neg(),
+                    _ => Some(value.not()),
+                };
+                match result {
+                    Some(result) => result,
+                    None => Self::error(&format!("Operand {op} cannot be used on type {value}"))?,
+                }
+            }
+            Node::If { condition, then, otherwise } => match self.evaluate(condition)?.as_bool() {
+                true => self.evaluate(then)?,
+                false => self.evaluate(otherwise)?,
+            },
             Node::Sum(expr) => self.get_sum(expr)?,
-            Node::Variable(v) => match self.constants.get(v) {
-                Some(s) => s.clone(),
-                None => panic!("No value for constant {v}"),
+            Node::Comprehension { expr, name, source, filter } => {
+                self.comprehension(expr, name, source, filter.as_deref())?
+            }
+            Node::Count { binding, predicate } => self.count(binding, predicate)?,
+            Node::Total { binding, expr } => self.total(binding, expr)?,
+            Node::Variable(v) => match self.lookup(v) {
+                Some(s) => s,
+                None => Err(Self::raw_error(&format!("No constant or parameter named {v}"))
+                    .with_help(&format!("constants are defined with `!{v} = ...`")))?,
             },
+            Node::Call { expr, args } => self.call(expr, args)?,
             Node::Array(a) => {
                 let evaled: Result<Vec<Value>, Error> =
                     a.iter().map(|item| self.evaluate(item)).collect();
                 Value::Array(evaled?)
             }
-            Node::Binary { left, op, right } => self.evaluate_binary(&left, &op, &right)?,
+            Node::Binary { left, op, right } => self.evaluate_binary(left, op, right)?,
             Node::Literal(v) => v.clone(),
             _ => Self::error("Expected expression found statement")?,
         }
     }
 
+    /// Finds the value of a name, checking the innermost function's
+    /// parameters before falling back to the program's constants.
+    pub fn lookup(&self, name: &str) -> Option<Value> {
+        let scopes = self.scopes.borrow();
+        match scopes.last().and_then(|scope| scope.get(name)) {
+            Some(value) => Some(value.clone()),
+            None => self.constants.get(name).cloned(),
+        }
+    }
+
     #[throws]
-    pub fn get_sum(&self, expr: &Box<PNode>) -> Value {
-        let eval = self.evaluate(&expr)?;
-        let neighbours = self.get_neighbours();
-        let sum = neighbours.iter().filter(|n| Value::Int(**n) == eval).collect::<Vec<&u8>>();
+    pub fn call(&self, expr: &PNode, args: &[PNode]) -> Value {
+        let name = match &expr.inner {
+            Node::Variable(name) => name,
+            _ => Self::error("Only functions can be called")?,
+        };
+        let function = match self.functions.get(name) {
+            Some(function) => function,
+            None => Err(Self::raw_error(&format!("No function named {name}"))
+                .with_help(&format!("functions are defined with `|> {name}(a, b) = ...`")))?,
+        };
+        let (params, body) = &function.inner;
 
-        Value::Int(sum.len() as u8)
+        if params.len() != args.len() {
+            let msg = format!(
+                "Function {name} takes {} arguments but {} were given",
+                params.len(),
+                args.len()
+            );
+            Err(Self::raw_error(&msg).with_label(&format!("{name} is defined here"), function.start, function.end))?;
+        }
+        if self.scopes.borrow().len() >= MAX_CALL_DEPTH {
+            Self::error(&format!("Maximum call depth of {MAX_CALL_DEPTH} exceeded in {name}"))?;
+        }
+
+        let args = args.iter().map(|arg| self.evaluate(arg)).collect::<Result<Vec<_>, _>>()?;
+        let scope = params.iter().cloned().zip(args).collect();
+        self.evaluate_in(scope, body)?
     }
 
-    pub fn get_neighbours(&self) -> Vec<u8> {
-        let (x, y) = (self.current_x as isize, self.current_y as isize);
-        let offsets = vec![(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, 1), (1, -1), (-1, -1)];
-        offsets.into_iter().map(|(ox, oy)| self.get_cell_signed(x + ox, y + oy)).collect()
-    }    
-
-    pub fn get_direction(&self, direction: &str) -> Value {
-        let x = self.current_x;
-        let y = self.current_y;
-        match direction {
-            "south" | "down" | "s" | "d" => Value::Int(self.get_cell(x, y + 1)),
-            "north" | "up" | "u" | "n" => {
-                if y == 0 { return Value::Int(0); }
-                Value::Int(self.get_cell(x, y - 1))
-            },
-            "centre" | "self" | "c" => Value::Int(self.get_cell(x, y)),
-            "east" | "right" | "e" | "r" => Value::Int(self.get_cell(x + 1, y)),
-            "west" | "left" | "w" | "l" => {
-                if x == 0 { return Value::Int(0); }
-                Value::Int(self.get_cell(x - 1, y))
-            },
-            "northwest" | "nw" => {
-                if x == 0 || y == 0 { return Value::Int(0); }
-                Value::Int(self.get_cell(x - 1, y - 1))
-            },
-            "northeast" | "ne" => {
-                if y == 0 { return Value::Int(0); }
-                Value::Int(self.get_cell(x + 1, y - 1))
+    /// Evaluates a node with `scope` as the innermost set of local names.
+    pub fn evaluate_in(&self, scope: HashMap<String, Value>, node: &PNode) -> Result<Value, Error> {
+        self.scopes.borrow_mut().push(scope);
+        let result = self.evaluate(node);
+        self.scopes.borrow_mut().pop();
+        result
+    }
+
+    /// Counts the items of a neighbourhood or array that satisfy a predicate.
+    #[throws]
+    pub fn count(&self, binding: &Option<(String, Box<PNode>)>, predicate: &PNode) -> Value {
+        let results = self.map_neighbourhood(binding, predicate, "count")?;
+        Value::Int(results.iter().filter(|result| result.as_bool()).count() as i64)
+    }
+
+    /// Adds up the items of a neighbourhood or array, giving a float if any of
+    /// them are floats.
+    #[throws]
+    pub fn total(&self, binding: &Option<(String, Box<PNode>)>, expr: &PNode) -> Value {
+        let (mut int, mut float, mut is_float) = (0i64, 0f64, false);
+        for item in self.map_neighbourhood(binding, expr, "sum")? {
+            match item {
+                Value::Int(i) => int = int.wrapping_add(i),
+                Value::Bool(b) => int = int.wrapping_add(b as i64),
+                Value::Float(f) => (float, is_float) = (float + f, true),
+                other => Self::error(&format!("Cannot sum {other}, expected a number"))?,
+            }
+        }
+
+        if is_float { Value::Float(int as f64 + float) } else { Value::Int(int) }
+    }
+
+    /// Evaluates `expr` once for each item of a neighbourhood or array.
+    ///
+    /// Without an explicit `name in source` binding, the first array-valued
+    /// directional in the expression is used, and each of its items is
+    /// substituted for that directional in turn. Expressions without one are
+    /// evaluated directly and must produce an array.
+    #[throws]
+    pub fn map_neighbourhood(
+        &self,
+        binding: &Option<(String, Box<PNode>)>,
+        expr: &PNode,
+        action: &str,
+    ) -> Vec<Value> {
+        let (name, items) = match binding {
+            Some((name, source)) => (name.clone(), self.evaluate(source)?),
+            None => match self.find_neighbourhood(expr)? {
+                Some((key, items)) => (key, items),
+                None => match self.evaluate(expr)? {
+                    Value::Array(items) => return items,
+                    _ => Self::error(&format!(
+                        "Nothing to {action} over, expected an array or a directional such as @all"
+                    ))?,
+                },
             },
-            "southwest" | "sw" => {
-                if x == 0 { return Value::Int(0); }
-                Value::Int(self.get_cell(x - 1, y + 1))
+        };
+        let items = match items {
+            Value::Array(items) => items,
+            other => Self::error(&format!("Cannot {action} over {other}, expected an array"))?,
+        };
+
+        let mut results = Vec::with_capacity(items.len());
+        for item in items {
+            let mut scope = self.scopes.borrow().last().cloned().unwrap_or_default();
+            scope.insert(name.clone(), item);
+            results.push(self.evaluate_in(scope, expr)?);
+        }
+        results
+    }
+
+    /// Builds an array from each item of `source` that passes `filter`, with
+    /// the item bound to `name`.
+    #[throws]
+    pub fn comprehension(&self, expr: &PNode, name: &str, source: &PNode, filter: Option<&PNode>) -> Value {
+        let items = match self.evaluate(source)? {
+            Value::Array(items) => items,
+            other => Err(Self::raw_error(&format!("Cannot loop over {other}, expected an array"))
+                .with_help("loop over a neighbourhood such as @all, or an array such as [1, 2, 3]")
+                .or_at(source.start, source.end))?,
+        };
+
+        let mut results = Vec::with_capacity(items.len());
+        for item in items {
+            let mut scope = self.scopes.borrow().last().cloned().unwrap_or_default();
+            scope.insert(name.to_string(), item);
+            if let Some(filter) = filter {
+                if !self.evaluate_in(scope.clone(), filter)?.as_bool() {
+                    continue;
+                }
+            }
+            results.push(self.evaluate_in(scope, expr)?);
+        }
+        Value::Array(results)
+    }
+
+    /// Finds the first directional in `node` that evaluates to an array,
+    /// without looking inside nested counts, sums and comprehensions, which
+    /// loop over their own, returning the name it is bound to while being
+    /// mapped over.
+    #[throws]
+    pub fn find_neighbourhood(&self, node: &PNode) -> Option<(String, Value)> {
+        match &node.inner {
+            Node::Directional { layer, name, arg } => {
+                match self.get_direction(self.layer_index(layer)?, name, arg.as_deref())? {
+                    array @ Value::Array(_) => Some((Self::binding_key(layer, name, arg), array)),
+                    _ => None,
+                }
+            }
+            Node::Count { .. } | Node::Total { .. } | Node::Comprehension { .. } => None,
+            inner => {
+                for child in inner.children() {
+                    if let Some(found) = self.find_neighbourhood(child)? {
+                        return Some(found);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    #[throws]
+    pub fn offset(&self, node: &PNode) -> isize {
+        let value = self.evaluate(node)?;
+        match value.as_offset() {
+            Some(offset) => offset,
+            None => Self::error(&format!("Expected a number as an offset, found {value}"))?,
+        }
+    }
+
+    /// The local name a directional is bound to while counting or summing
+    /// over it.
+    fn binding_key(layer: &Option<String>, name: &str, arg: &Option<Box<PNode>>) -> String {
+        let layer = layer.as_ref().map(|layer| format!("{layer}.")).unwrap_or_default();
+        match arg {
+            Some(arg) => format!("@{layer}{name}({:?})", arg.inner),
+            None => format!("@{layer}{name}"),
+        }
+    }
+
+    /// Finds the offsets of a neighbourhood argument, either the name of a
+    /// declared neighbourhood or the radius of a Moore neighbourhood.
+    #[throws]
+    pub fn neighbourhood_offsets(&self, node: &PNode) -> Vec<(isize, isize)> {
+        if let Node::Variable(name) = &node.inner {
+            if let Some(offsets) = self.neighbourhoods.get(name) {
+                return offsets.clone();
+            }
+        }
+        match self.evaluate(node)? {
+            Value::Int(radius) if radius > MAX_RADIUS as i64 => Err(Self::raw_error(&format!(
+                "A neighbourhood's radius can be at most {MAX_RADIUS}, found {radius}"
+            ))
+            .or_at(node.start, node.end))?,
+            Value::Int(radius) if radius >= 0 => shape_offsets(&Shape::Moore(radius as usize)),
+            other => Self::error(&format!("Expected a neighbourhood or radius, found {other}"))?,
+        }
+    }
+
+    #[throws]
+    pub fn get_sum(&self, expr: &PNode) -> Value {
+        let (eval, offsets) = match &expr.inner {
+            Node::Tuple(args) if args.len() == 2 => {
+                (self.evaluate(&args[0])?, self.neighbourhood_offsets(&args[1])?)
+            }
+            _ => (self.evaluate(expr)?, MOORE.to_vec()),
+        };
+        let neighbours = self.get_neighbours(self.current_layer.get(), &offsets);
+        let sum = neighbours.iter().filter(|n| Value::Int(**n) == eval).count();
+
+        Value::Int(sum as i64)
+    }
+
+    pub fn get_neighbours(&self, layer: usize, offsets: &[(isize, isize)]) -> Vec<i64> {
+        let (x, y) = (self.current_x as isize, self.current_y as isize);
+        offsets
+            .iter()
+            .map(|(ox, oy)| self.get_cell_signed(layer, x.saturating_add(*ox), y.saturating_add(*oy)))
+            .collect()
+    }
+
+    pub fn neighbour_array(&self, layer: usize, offsets: &[(isize, isize)]) -> Value {
+        Value::Array(self.get_neighbours(layer, offsets).into_iter().map(Value::Int).collect())
+    }
+
+    /// Reads a directional. Array directionals take an optional neighbourhood
+    /// argument, and keep only the direct or diagonal cells of it for `@dir`
+    /// and `@diag`.
+    #[throws]
+    pub fn get_direction(&self, layer: usize, direction: &str, arg: Option<&PNode>) -> Value {
+        let offsets = match arg {
+            Some(arg) => Some(self.neighbourhood_offsets(arg)?),
+            None => None,
+        };
+        match (direction, offsets) {
+            ("south" | "down" | "s" | "d", None) => self.relative(layer, 0, 1),
+            ("north" | "up" | "u" | "n", None) => self.relative(layer, 0, -1),
+            ("centre" | "self" | "c", None) => self.relative(layer, 0, 0),
+            ("east" | "right" | "e" | "r", None) => self.relative(layer, 1, 0),
+            ("west" | "left" | "w" | "l", None) => self.relative(layer, -1, 0),
+            ("northwest" | "nw", None) => self.relative(layer, -1, -1),
+            ("northeast" | "ne", None) => self.relative(layer, 1, -1),
+            ("southwest" | "sw", None) => self.relative(layer, -1, 1),
+            ("southeast" | "se", None) => self.relative(layer, 1, 1),
+            ("x", None) => Value::Int(self.current_x as i64),
+            ("y", None) => Value::Int(self.current_y as i64),
+            ("generation" | "gen", None) => {
+                self.read_time.set(true);
+                Value::Int(self.generation as i64)
+            }
+            ("random" | "rand", None) => {
+                self.read_time.set(true);
+                Value::Float(self.random.borrow_mut().next_float())
+            }
+            ("width", None) => {
+                let row = self.memory.get(layer).and_then(|grid| grid.get(self.current_y));
+                Value::Int(row.map_or(0, |row| row.len()) as i64)
+            }
+            ("height", None) => Value::Int(self.memory.get(layer).map_or(0, |grid| grid.len()) as i64),
+            ("all", None) => self.neighbour_array(layer, &MOORE),
+            ("direct" | "dir", None) => self.neighbour_array(layer, &DIRECT),
+            ("diagonals" | "diag", None) => self.neighbour_array(layer, &DIAGONAL),
+            ("all", Some(offsets)) => self.neighbour_array(layer, &offsets),
+            ("direct" | "dir", Some(offsets)) => {
+                let direct = offsets.into_iter().filter(|(dx, dy)| *dx == 0 || *dy == 0);
+                self.neighbour_array(layer, &direct.collect::<Vec<_>>())
+            }
+            ("diagonals" | "diag", Some(offsets)) => {
+                let diagonal = offsets.into_iter().filter(|(dx, dy)| dx.abs() == dy.abs());
+                self.neighbour_array(layer, &diagonal.collect::<Vec<_>>())
+            }
+            (_, None) => match self.neighbourhoods.get(direction) {
+                Some(offsets) => self.neighbour_array(layer, offsets),
+                None => Err(self.unknown_directional(direction))?,
             },
-            "southeast" | "se" => Value::Int(self.get_cell(x + 1, y + 1)),
-            _ => Value::Unknown,
+            (_, Some(_)) => Self::error(&format!("@{direction} does not take a neighbourhood"))?,
         }
     }
 
+    fn unknown_directional(&self, name: &str) -> Error {
+        let mut names = vec![
+            "north", "south", "east", "west", "northeast", "northwest", "southeast", "southwest", "centre", "x",
+            "y", "gen", "random", "width", "height", "all", "dir", "diag",
+        ];
+        let mut neighbourhoods = self.neighbourhoods.keys().map(String::as_str).collect::<Vec<_>>();
+        neighbourhoods.sort_unstable();
+        names.extend(neighbourhoods);
+        let help = format!("the directionals are @{}", names.join(", @"));
+        Self::raw_error(&format!("No directional named @{name}")).with_help(&help)
+    }
+
     #[throws]
     pub fn evaluate_binary(
         &self,
-        left: &Box<Positioned<Node>>,
+        left: &PNode,
         op: &Token,
-        right: &Box<Positioned<Node>>,
+        right: &PNode,
     ) -> Value {
-        let start = left.start.min(right.start);
-        let end = left.end.max(right.end);
-
         let left = self.evaluate(left)?;
         let right = self.evaluate(right)?;
+        if matches!(right, Value::Int(0)) || matches!(right, Value::Float(f) if f == 0.0) {
+            match op {
+                Token::Slash => Self::error("Division by zero")?,
+                Token::Mod => Self::error("Modulo by zero")?,
+                _ => {}
+            }
+        }
         let result = match op {
             Token::Minus => left.sub(&right),
             Token::Plus => left.add(&right),
@@ -261,15 +1230,18 @@ impl Interpreter {
             Token::Or => left.or(&right),
             Token::And => left.and(&right),
             Token::Mod => left.modulus(&right),
-            _ => Some(Value::Bool(match op {
-                Token::Equals => left == right,
-                Token::NotEquals => left != right,
-                Token::Greater => left > right,
-                Token::Lesser => left < right,
-                Token::GreaterThan => left <= right,
-                Token::LesserThan => left >= right,
-                _ => unreachable!(),
-            })),
+            Token::Carat => left.pow(&right),
+            Token::Equals => Some(Value::Bool(left.equals(&right))),
+            Token::NotEquals => Some(Value::Bool(!left.equals(&right))),
+            _ => left.compare(&right).map(|ordering| {
+                Value::Bool(match op {
+                    Token::Greater => ordering.is_gt(),
+                    Token::Lesser => ordering.is_lt(),
+                    Token::GreaterThan => ordering.is_ge(),
+                    Token::LesserThan => ordering.is_le(),
+                    _ => unreachable!(),
+                })
+            }),
         };
 
         match result {
@@ -281,8 +1253,497 @@ impl Interpreter {
         }
     }
 
+    /// Converts a value to a number, for uses that need one, pointing at
+    /// `node` if it isn't.
+    fn number(value: Value, node: &PNode, usage: &str) -> Result<i64, Error> {
+        match value.as_num() {
+            Some(number) => Ok(number),
+            None => Self::error(&format!("Expected a number {usage}, found {value}"))
+                .map_err(|err| err.or_at(node.start, node.end)),
+        }
+    }
+
     pub fn error<T>(msg: &str) -> Result<T, Error> {
-        let msg = msg.to_string();
-        Err(Error { msg, start: Position::new(), end: Position::new() })
+        Err(Self::raw_error(msg))
+    }
+
+    /// An error without a position yet, which `evaluate` will place at the
+    /// expression being evaluated.
+    pub fn raw_error(msg: &str) -> Error {
+        Error::new(msg, Position::end(), Position::end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::render::Text;
+
+    fn load(program: &str) -> Result<Interpreter, Error> {
+        let tokens = Lexer::new(program.to_string()).scan_tokens()?;
+        let ast = Parser::new(tokens).parse().map_err(|mut errors| errors.remove(0))?;
+        let mut interp = Interpreter::new(ast);
+        interp.load_instructions()?;
+        Ok(interp)
+    }
+
+    /// Loads a program and runs a single generation of it, expecting an error.
+    fn error(program: &str) -> Error {
+        let result = load(program).and_then(|mut interp| interp.match_cells());
+        result.expect_err("expected an error")
+    }
+
+    #[test]
+    fn undefined_constant() {
+        let err = error("!a = 0\n~a;2~\n_ |> missing");
+        assert_eq!(err.msg, "No constant or parameter named missing");
+        assert_eq!(err.start.line, 3);
+        assert_eq!(err.details.location, Some(Location { x: 0, y: 0, generation: 0 }));
+    }
+
+    #[test]
+    fn function_calls_bind_their_arguments() {
+        let program = "!a = 0\n!n = 5\n~a;2~\n|> add(a, b) = a + b\n|> twice(n) = n * 2\n\
+                       _ : @x == 0 |> add(2, 3)\n_ |> twice(1) + n\n";
+        let mut interp = load(program).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![5, 7]]);
+    }
+
+    #[test]
+    fn wrong_number_of_arguments() {
+        let err = error("!a = 0\n~a;2~\n|> f(n) = n\n_ |> f(1, 2)");
+        assert_eq!(err.msg, "Function f takes 1 arguments but 2 were given");
+        assert_eq!(err.start.line, 4);
+        assert_eq!(err.details.labels[0].msg, "f is defined here");
+        assert_eq!(err.details.labels[0].start.line, 3);
+    }
+
+    #[test]
+    fn call_depth_is_limited() {
+        let err = error("!a = 0\n~a;2~\n|> f(n) = f(n + 1)\n_ |> f(0)");
+        assert_eq!(err.msg, "Maximum call depth of 64 exceeded in f");
+    }
+
+    #[test]
+    fn counts() {
+        let mut interp = load("!a = 0\n!b = 2\n~b;1 a;1 b;1~\n_ |> #[@dir > 1]").unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![0, 2, 0]]);
+
+        let mut interp = load("!a = 0\n~a;2~\n_ |> #[n in [1, 2, 3]: n >= 2]").unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![2, 2]]);
+
+        let err = error("!a = 0\n~a;2~\n_ |> #[1 > 0]");
+        assert_eq!(err.msg, "Nothing to count over, expected an array or a directional such as @all");
+        assert_eq!(err.start.line, 3);
+    }
+
+    #[test]
+    fn layers_read_and_write_each_other() {
+        let program = "!a = 0\n!b = 1\n~a;2|b;2~\n~heat: b;2|a;2~\n_ |> @heat.n\n_ |heat> @centre + @main.s + 1\n";
+        let mut interp = load(program).unwrap();
+        assert_eq!(interp.layers(), ["main", "heat"]);
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![0, 0], vec![1, 1]]);
+        assert_eq!(interp.grid(1), &vec![vec![3, 3], vec![1, 1]]);
+    }
+
+    #[test]
+    fn unknown_layer() {
+        let err = error("!a = 0\n~a;2~\n_ |> @cold.n");
+        assert_eq!(err.msg, "No layer named cold");
+        assert_eq!(err.details.help.as_deref(), Some("the layers are main"));
+
+        let err = load("!a = 0\n~a;2~\n_ |cold> 1").err().unwrap();
+        assert_eq!(err.msg, "No layer named cold");
+        assert_eq!(err.start.line, 3);
+    }
+
+    #[test]
+    fn layers_must_be_rectangular_and_the_same_shape() {
+        let err = load("!a = 0\n~a;3|a;1~").err().unwrap();
+        assert_eq!(err.msg, "Row 2 of layer main has 1 cells, but its first row has 3");
+        assert_eq!((err.start.line, err.start.col, err.end.col), (2, 2, 10));
+
+        let err = load("!a = 0\n~a;3~\n~heat: a;2~").err().unwrap();
+        assert_eq!(err.msg, "Layer heat is a different shape to layer main");
+        assert_eq!(err.start.line, 3);
+        assert_eq!(err.details.labels[0].start.line, 2);
+
+        let interp = load("sys {\n    size = (3, 2)\n}\n!a = 0\n~a;3|a;1~").unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![0, 0, 0], vec![0, 0, 0]]);
+    }
+
+    #[test]
+    fn overridden_constants_must_be_defined() {
+        let tokens = Lexer::new("!a = 0\n!v = 1\n~a;2~\n_ |> v".to_string()).scan_tokens().unwrap();
+        let mut interp = Interpreter::new(Parser::new(tokens).parse().unwrap());
+        interp.override_constant("v".to_string(), Value::Int(5));
+        interp.override_constant("vv".to_string(), Value::Int(5));
+        interp.load_instructions().unwrap();
+        interp.check_override("v").unwrap();
+        let err = interp.check_override("vv").unwrap_err();
+        assert_eq!(err.msg, "The program has no constant named vv");
+        assert_eq!(err.details.help.as_deref(), Some("the constants are a, v"));
+
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![5, 5]]);
+    }
+
+    #[test]
+    fn random_numbers_repeat_with_a_seed() {
+        let grid = |seed: u64| {
+            let mut interp = load("!a = 0\n~a;8~\n_ |> @random * 1000").unwrap().with_seed(seed);
+            interp.match_cells().unwrap();
+            interp.grid(0).clone()
+        };
+        assert_eq!(grid(3), grid(3));
+        assert_ne!(grid(3), grid(4));
+        assert!(grid(3)[0].iter().all(|cell| (0..1000).contains(cell)));
+    }
+
+    #[test]
+    fn unknown_identifier_in_memory() {
+        let err = error("!a = 0\n~a;2 b;2~");
+        assert!(err.msg.starts_with("No constant named b"), "{}", err.msg);
+        assert_eq!(err.start.line, 2);
+        assert_eq!(err.details.location, None);
+    }
+
+    #[test]
+    fn non_numeric_cell() {
+        let err = error("!a = 0\n~a;2~\n_ |> \"text\"");
+        assert_eq!(err.msg, "Expected a number to store in a cell, found String");
+        assert_eq!(err.start.line, 3);
+
+        let err = error("!a = \"text\"\n~a;2~");
+        assert_eq!(err.msg, "Expected a number to store in a cell, found String");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let err = error("!a = 0\n~a;2~\n_ |> 1 / @centre");
+        assert_eq!(err.msg, "Division by zero");
+        assert_eq!(err.start.line, 3);
+    }
+
+    #[test]
+    fn division_by_float_zero() {
+        let err = error("!a = 0\n~a;2~\n_ |> 5 / 0.0");
+        assert_eq!(err.msg, "Division by zero");
+        let err = error("!a = 0\n~a;2~\n_ |> 5.0 / @centre");
+        assert_eq!(err.msg, "Division by zero");
+    }
+
+    #[test]
+    fn unknown_directional() {
+        let err = error("!a = 0\n~a;2~\n@nrth |> 1");
+        assert_eq!(err.msg, "No directional named @nrth");
+        assert_eq!((err.start.line, err.start.col), (3, 2));
+        assert!(err.details.help.as_deref().unwrap().starts_with("the directionals are @north, @south"));
+    }
+
+    #[test]
+    fn modulo_by_zero() {
+        let err = error("!a = 0\n~a;2~\n_ |> 1 % @centre");
+        assert_eq!(err.msg, "Modulo by zero");
+        assert_eq!(err.details.location, Some(Location { x: 0, y: 0, generation: 0 }));
+    }
+
+    #[test]
+    fn division_by_non_zero() {
+        let mut interp = load("!a = 7\n~a;2~\n_ |> @centre / 2").unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![3, 3]]);
+    }
+
+    #[test]
+    fn if_evaluates_only_the_branch_taken() {
+        let mut interp = load("!a = 0\n~a;2~\n_ |> if @x == 0 then 5 else 1 / @centre").unwrap();
+        interp.match_cells().unwrap_err();
+        let mut interp = load("!a = 0\n~a;2~\n_ |> if @x < 5 then 5 else 1 / @centre").unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![5, 5]]);
+    }
+
+    #[test]
+    fn comprehensions() {
+        let program = "!a = 0\n!b = 1\n~a;1 b;2|b;1 a;1 b;1~\n\
+                       _ |> =[[n * 2 for n in @all if n > 0]]\n";
+        let mut interp = load(program).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![4, 6, 4], vec![2, 8, 4]]);
+
+        let err = error("!a = 0\n~a;2~\n_ |> =[[n for n in @centre]]");
+        assert_eq!(err.msg, "Cannot loop over Int, expected an array");
+        assert_eq!(err.start.col, 21);
+    }
+
+    const RULES: &str = "!a = 0\n~a;2~\nrule one {\n    0 |> 1\n}\nrule two {\n    0 |> 2\n}\n";
+
+    #[test]
+    fn rules_run_in_order_unless_disabled_or_reordered() {
+        let mut interp = load(RULES).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![1, 1]]);
+
+        let mut interp = load(RULES).unwrap();
+        interp.disable_rule("one").unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![2, 2]]);
+
+        let mut interp = load(RULES).unwrap();
+        interp.order_rules(&["two".to_string()]).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![2, 2]]);
+
+        let err = load(RULES).unwrap().disable_rule("three").unwrap_err();
+        assert_eq!(err.details.help.as_deref(), Some("the rules are one, two"));
+    }
+
+    #[test]
+    fn errors_name_their_rule() {
+        let err = error("!a = 0\n~a;2~\nrule divide {\n    _ |> 1 / @centre\n}");
+        assert_eq!(err.details.notes, ["in rule divide"]);
+    }
+
+    #[test]
+    fn size_setting_crops_and_pads_layers() {
+        let interp = load("sys {\n    size = (3, 2)\n}\n!a = 0\n!b = 1\n~b;4|b;4|b;4~").unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![1, 1, 1], vec![1, 1, 1]]);
+
+        let interp = load("sys {\n    size = (2, 3)\n}\n!b = 1\n~b;1~").unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![1, 0], vec![0, 0], vec![0, 0]]);
+
+        let err = load("sys {\n    size = (2, -3)\n}").err().unwrap();
+        assert_eq!(err.msg, "Expected a number that isn't negative as a height, found -3");
+        assert_eq!(err.start.col, 17);
+
+        let err = load("sys {\n    size = (100000000000, 100000000000)\n}").err().unwrap();
+        assert_eq!(err.msg, "A grid can be at most 4096 cells wide");
+        assert_eq!(err.start.col, 14);
+    }
+
+    #[test]
+    fn tick_seed_and_palette_settings() {
+        let program = "sys {\n    tick = 50\n    seed = 3\n    palette = [[1, 2, 3]]\n}\n!a = 0\n~a;1~";
+        let interp = load(program).unwrap();
+        assert_eq!(interp.tick(), Duration::from_millis(50));
+        assert_eq!(interp.palette(), [(1, 2, 3)]);
+        let seeded = Interpreter::new(Vec::new()).with_seed(3);
+        assert_eq!(interp.random.borrow_mut().next_u64(), seeded.random.borrow_mut().next_u64());
+
+        let err = load("sys {\n    palette = [[0, 0, 256]]\n}").err().unwrap();
+        assert_eq!(err.msg, "Colour channels go from 0 to 255");
+    }
+
+    /// Runs a generation of a two row grid, numbered 1 to 6, with the given
+    /// boundary and each cell set to the one at an offset from it.
+    fn offset_under(boundary: &str, dx: isize, dy: isize) -> Grid {
+        let program = format!(
+            "sys {{\n    boundary = {boundary}\n}}\n!a = 1\n!b = 2\n!c = 3\n!d = 4\n!e = 5\n!f = 6\n\
+             ~a;1 b;1 c;1|d;1 e;1 f;1~\n_ |> @({dx}, {dy})\n"
+        );
+        let mut interp = load(&program).unwrap();
+        interp.match_cells().unwrap();
+        interp.grid(0).clone()
+    }
+
+    #[test]
+    fn huge_offsets() {
+        let (max, min) = (isize::MAX, -isize::MAX);
+        assert_eq!(offset_under("0", max, 0), vec![vec![0, 0, 0], vec![0, 0, 0]]);
+        assert_eq!(offset_under("9", 0, min), vec![vec![9, 9, 9], vec![9, 9, 9]]);
+        for boundary in ["torus", "reflect", "cylinder", "cylinder(y)"] {
+            offset_under(boundary, max, min);
+            offset_under(boundary, min, max);
+        }
+    }
+
+    #[test]
+    fn huge_neighbourhood_offsets() {
+        let (max, min) = (isize::MAX, -isize::MAX);
+        let program = format!("!a = 0\n!b = 1\n~b;2|b;2~\nneighbourhood k = [({max}, 0), (0, {min})]\n_ |> =[@k]\n");
+        let mut interp = load(&program).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![0, 0], vec![0, 0]]);
+    }
+
+    #[test]
+    fn torus_boundary() {
+        assert_eq!(offset_under("torus", -1, 0), vec![vec![3, 1, 2], vec![6, 4, 5]]);
+        assert_eq!(offset_under("torus", 0, -1), vec![vec![4, 5, 6], vec![1, 2, 3]]);
+        assert_eq!(offset_under("torus", 4, 3), vec![vec![5, 6, 4], vec![2, 3, 1]]);
+    }
+
+    #[test]
+    fn reflect_boundary() {
+        assert_eq!(offset_under("reflect", -1, 0), vec![vec![1, 1, 2], vec![4, 4, 5]]);
+        assert_eq!(offset_under("reflect", 2, 0), vec![vec![3, 3, 2], vec![6, 6, 5]]);
+        assert_eq!(offset_under("reflect", 0, 1), vec![vec![4, 5, 6], vec![4, 5, 6]]);
+        assert_eq!(offset_under("reflect", 0, -2), vec![vec![4, 5, 6], vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn cylinder_boundary() {
+        assert_eq!(offset_under("cylinder(y)", -1, 0), vec![vec![0, 1, 2], vec![0, 4, 5]]);
+        assert_eq!(offset_under("cylinder(y)", 0, 1), vec![vec![4, 5, 6], vec![1, 2, 3]]);
+        assert_eq!(offset_under("cylinder", -1, 0), vec![vec![3, 1, 2], vec![6, 4, 5]]);
+        assert_eq!(offset_under("cylinder", 0, 1), vec![vec![4, 5, 6], vec![0, 0, 0]]);
+    }
+
+    #[test]
+    fn constant_boundary() {
+        assert_eq!(offset_under("constant(9)", -1, 0), vec![vec![9, 1, 2], vec![9, 4, 5]]);
+        assert_eq!(offset_under("7", 0, 1), vec![vec![4, 5, 6], vec![7, 7, 7]]);
+        assert_eq!(offset_under("0", 1, 1), vec![vec![5, 6, 0], vec![0, 0, 0]]);
+    }
+
+    const BLINKER: &str = "!d = 0\n!a = 1\n~d;5|d;5|d;1 a;3 d;1|d;5|d;5~\n\
+                           1: #(1) < 2 || #(1) > 3 |> 0\n0: #(1) == 3 |> 1\n";
+
+    #[test]
+    fn synchronous_blinker_oscillates() {
+        let mut interp = load(BLINKER).unwrap();
+        let horizontal = interp.grid(0).clone();
+        interp.match_cells().unwrap();
+        let vertical = vec![
+            vec![0, 0, 0, 0, 0],
+            vec![0, 0, 1, 0, 0],
+            vec![0, 0, 1, 0, 0],
+            vec![0, 0, 1, 0, 0],
+            vec![0, 0, 0, 0, 0],
+        ];
+        assert_eq!(interp.grid(0), &vertical);
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &horizontal);
+    }
+
+    #[test]
+    fn in_place_updates_see_earlier_cells() {
+        let mut interp = load(BLINKER).unwrap();
+        interp.update_mode = UpdateMode::InPlace;
+        interp.match_cells().unwrap();
+        // (2, 1) is born first, so (3, 1) sees three neighbours instead of two.
+        let expected = vec![
+            vec![0, 0, 0, 0, 0],
+            vec![0, 0, 1, 1, 0],
+            vec![0, 1, 0, 1, 0],
+            vec![0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0],
+        ];
+        assert_eq!(interp.grid(0), &expected);
+    }
+
+    #[test]
+    fn shapes() {
+        assert_eq!(shape_offsets(&Shape::VonNeumann(1)), [(0, -1), (-1, 0), (1, 0), (0, 1)]);
+        assert_eq!(shape_offsets(&Shape::VonNeumann(2)).len(), 12);
+        assert_eq!(shape_offsets(&Shape::Hex(1)), [(0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1)]);
+        assert_eq!(shape_offsets(&Shape::Hex(2)).len(), 18);
+        assert_eq!(shape_offsets(&Shape::Moore(2)).len(), 24);
+        assert_eq!(shape_offsets(&Shape::Moore(0)), []);
+    }
+
+    #[test]
+    fn neighbourhood_arguments() {
+        // Every cell, on and off the grid, is 1, so each sum is the size of
+        // the neighbourhood it reads.
+        let sums = ["=[@all(vn)]", "=[@all(2)]", "=[@dir(2)]", "=[@diag(2)]", "=[@hexes]", "=[@all(knight)]"];
+        let mut program = String::from(
+            "sys {\n    boundary = 1\n}\nneighbourhood vn = vonneumann\nneighbourhood hexes = hex(1)\n\
+             neighbourhood knight = [(1, 2), (2, 1), (-1, 2)]\n!a = 1\n~a;6~\n",
+        );
+        for (x, sum) in sums.iter().enumerate() {
+            program.push_str(&format!("_ : @x == {x} |> {sum}\n"));
+        }
+        let mut interp = load(&program).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![4, 24, 8, 8, 6, 3]]);
+    }
+
+    #[test]
+    fn neighbourhood_argument_radius_is_capped() {
+        let err = error("!a = 0\n~a;2~\n_ |> =[@all(100000)]");
+        assert_eq!(err.msg, "A neighbourhood's radius can be at most 64, found 100000");
+        assert_eq!((err.start.col, err.end.col), (14, 19));
+    }
+
+    #[test]
+    fn halt_runs_before_or_after_a_write() {
+        for program in ["_ |> @centre + 1\n_ |> halt(3)", "_ |> halt(3)\n_ |> @centre + 1"] {
+            let mut interp = load(&format!("!a = 0\n~a;2~\n{program}")).unwrap();
+            let changed = interp.match_cells().unwrap();
+            assert_eq!(interp.halt(changed), Some(Halt::Rule(3)), "{program}");
+            assert_eq!(interp.grid(0), &vec![vec![1, 1]], "{program}");
+        }
+    }
+
+    #[test]
+    fn generation_limit_is_checked_before_each_generation() {
+        for (limit, generations) in [(0, 0), (2, 2)] {
+            let mut interp = load("!a = 0\n~a;2~\n_ |> @centre + 1").unwrap();
+            interp.set_generation_limit(limit);
+            let mut text = Text::new(Box::new(std::io::sink()), Box::new(std::io::sink()), false);
+            assert_eq!(interp.run(&mut text).unwrap(), Halt::Limit);
+            assert_eq!(interp.generation(), generations);
+            assert_eq!(interp.grid(0), &vec![vec![generations as i64; 2]]);
+        }
+    }
+
+    /// Runs a program until it halts, returning why and after how many
+    /// generations.
+    fn run(program: &str) -> (Halt, usize) {
+        let mut interp = load(program).unwrap();
+        let mut text = Text::new(Box::new(std::io::sink()), Box::new(std::io::sink()), false);
+        let halt = interp.run(&mut text).unwrap();
+        (halt, interp.generation())
+    }
+
+    #[test]
+    fn reading_the_generation_or_random_is_never_stable() {
+        let limit = "sys {\n    generations = 6\n}\n!a = 0\n~a;2~\n";
+        assert_eq!(run(&format!("{limit}_ : @gen % 3 == 0 |> 1 - @centre")), (Halt::Limit, 6));
+        assert_eq!(run(&format!("{limit}_ : @random > 2 |> 1")), (Halt::Limit, 6));
+        assert_eq!(run(&format!("{limit}_ : @gen > 9 |> 1\n_ : 1 > 2 |> 1")), (Halt::Limit, 6));
+
+        let program = format!("sys {{\n    stable = halt\n}}\n{limit}_ : @gen % 3 == 0 |> 1 - @centre");
+        assert_eq!(run(&program), (Halt::Stable, 2));
+        assert_eq!(run("!a = 0\n~a;2~\n_ |> 0"), (Halt::Stable, 1));
+    }
+
+    #[test]
+    fn printing_is_never_stable() {
+        let limit = "sys {\n    generations = 3\n}\n!a = 0\n~a;1~\n";
+        assert_eq!(run(&format!("{limit}_ $ 72")), (Halt::Limit, 3));
+        assert_eq!(run(&format!("{limit}_ : @gen == 0 $ 72\n_ : @gen == 1 $ 105")), (Halt::Limit, 3));
+    }
+
+    #[test]
+    fn expression_as_statement() {
+        let position = Position { line: 2, col: 3, end: false };
+        let node = Positioned { inner: Node::Literal(Value::Int(1)), start: position, end: position };
+        let err = Interpreter::new(vec![node]).load_instructions().unwrap_err();
+        assert_eq!(err.msg, "Expected a statement");
+        assert_eq!(err.start, position);
+    }
+
+    #[test]
+    fn operators() {
+        let results = [
+            "2 ^ 3 ^ 2", "10 - 4 - 3", "-2 ^ 2", "1 <= 1.5", "2 >= 3", "!0 * 3", "1 == 1.0", "2.0 != 2",
+            "16777217 > 16777216.0", "2.0 ^ 4294967296", "2 ^ -4294967296",
+        ];
+        let mut program = String::from("!a = 0\n~a;11~\n");
+        for (x, result) in results.iter().enumerate() {
+            program.push_str(&format!("_ : @x == {x} |> {result}\n"));
+        }
+        let mut interp = load(&program).unwrap();
+        interp.match_cells().unwrap();
+        assert_eq!(interp.grid(0), &vec![vec![512, 3, -4, 1, 0, 3, 1, 0, 1, i64::MAX, 0]]);
     }
 }
@@ -3,7 +3,7 @@ use crate::positioned::Positioned;
 use crate::value::Value;
 use fehler::throws;
 use macros::{match_tokens, match_two};
-use std::{f32, str::FromStr};
+use std::str::FromStr;
 use strum_macros::{EnumIs, EnumString};
 
 #[derive(Debug)]
@@ -11,6 +11,86 @@ pub struct Error {
     pub msg: String,
     pub start: Position,
     pub end: Position,
+    /// The source the positions are in, numbered in the order they were
+    /// given to `Diagnostics`. The program itself is 0.
+    pub file: usize,
+    /// Boxed, since most errors have none and errors are returned often.
+    pub details: Box<Details>,
+}
+
+/// What else is known about an error, beyond where it is.
+#[derive(Debug, Default)]
+pub struct Details {
+    /// The cell being computed when a runtime error happened.
+    pub location: Option<Location>,
+    /// Other parts of the source that help explain the error.
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+/// A cell of the grid in a particular generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub x: usize,
+    pub y: usize,
+    pub generation: usize,
+}
+
+/// A message pointing at a span of the source.
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub msg: String,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Error {
+    pub fn new(msg: &str, start: Position, end: Position) -> Self {
+        Error {
+            msg: msg.to_string(),
+            start,
+            end,
+            file: 0,
+            details: Box::default(),
+        }
+    }
+
+    /// Places an error that doesn't have a position in the source yet.
+    pub fn or_at(self, start: Position, end: Position) -> Self {
+        match self.start.end {
+            true => Error { start, end, ..self },
+            false => self,
+        }
+    }
+
+    pub fn with_label(mut self, msg: &str, start: Position, end: Position) -> Self {
+        self.details.labels.push(Label { msg: msg.to_string(), start, end });
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.details.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.details.help = Some(help.to_string());
+        self
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::new(&format!("Failed to write output: {err}"), Position::end(), Position::end())
+    }
+}
+
+/// Lets a single error be returned where several can be.
+impl From<Error> for Vec<Error> {
+    fn from(error: Error) -> Self {
+        vec![error]
+    }
 }
 
 pub struct Lexer {
@@ -65,20 +145,31 @@ impl Lexer {
 
                 '"' => {
                     let mut string = String::new();
-                    while self.peek().unwrap() != '"' {
-                        string.push(self.next().unwrap());
+                    loop {
+                        match self.next() {
+                            Ok('"') => break,
+                            Ok('\\') => string.push(self.escape()?),
+                            Ok(c) => string.push(c),
+                            Err(_) => self.error_at_start("Unterminated string, expected a closing \"")?,
+                        }
                     }
-                    let _ = self.next();
                     tokens.push(self.wrap(Token::Literal(Value::String(string))));
                 }
 
                 '\'' => {
-                    let char = self.next().unwrap();
-                    if let '\'' = self.next()? {
-                        tokens.push(self.wrap(Token::Literal(Value::Char(char))));
-                    } else {
-                        self.error("Char closing tag not found.")?;
+                    let char = match self.next() {
+                        Ok('\'') => self.error("Empty char literal, expected a character between the quotes")?,
+                        Ok('\n') | Err(_) => self.error_at_start("Unterminated char literal, expected a closing '")?,
+                        Ok('\\') => self.escape()?,
+                        Ok(c) => c,
+                    };
+                    if !self.matches('\'')? {
+                        match self.peek() {
+                            Ok('\n') | Err(_) => self.error_at_start("Unterminated char literal, expected a closing '")?,
+                            Ok(_) => self.error("Char literals hold a single character, expected a closing '")?,
+                        }
                     }
+                    tokens.push(self.wrap(Token::Literal(Value::Char(char))));
                 }
 
                 '/' => {
@@ -94,10 +185,10 @@ impl Lexer {
                 '0'..='9' => {
                     let mut number = token.to_string();
                     while let Ok(c) = self.peek() {
-                        if !(c.is_numeric() || c == '.' || c == '_') {
+                        if !(is_alphanumeric(c) || c == '.') {
                             break;
                         }
-                        number.push(self.next().unwrap());
+                        number.push(self.next()?);
                     }
                     tokens.push(self.wrap(self.number(number)?));
                 }
@@ -108,7 +199,7 @@ impl Lexer {
                         if !is_alphanumeric(peeked) {
                             break;
                         }
-                        ident.push(self.next().unwrap());
+                        ident.push(self.next()?);
                     }
 
                     match Keyword::from_str(&ident) {
@@ -117,7 +208,8 @@ impl Lexer {
                     }
                 }
 
-                _ => {}
+                c if c.is_whitespace() => {}
+                c => self.error(&format!("Unexpected character {c:?}"))?,
             };
         }
 
@@ -137,10 +229,7 @@ impl Lexer {
     }
 
     pub fn peek_not(&mut self, c: char) -> bool {
-        match self.peek() {
-            Ok(peeked) if peeked != c => true,
-            _ => false,
-        }
+        matches!(self.peek(), Ok(peeked) if peeked != c)
     }
 
     pub fn next(&mut self) -> Result<char, Error> {
@@ -164,15 +253,56 @@ impl Lexer {
 
     #[throws]
     pub fn matches(&mut self, item: char) -> bool {
-        if self.program.get(self.index) == Some(&&item) {
+        if self.program.get(self.index) == Some(&item) {
             self.next()?;
             return true;
         }
         return false;
     }
 
+    /// Reads the rest of an escape sequence, after its backslash: one of
+    /// `\n`, `\t`, `\r`, `\\`, `\'`, `\"`, `\0`, or `\u{...}` with up to six
+    /// hex digits.
+    #[throws]
+    pub fn escape(&mut self) -> char {
+        let start = self.current;
+        match self.next() {
+            Ok('n') => '\n',
+            Ok('t') => '\t',
+            Ok('r') => '\r',
+            Ok('\\') => '\\',
+            Ok('\'') => '\'',
+            Ok('"') => '"',
+            Ok('0') => '\0',
+            Ok('u') => {
+                if !self.matches('{')? {
+                    self.error_from(start, "Expected { after \\u, as in \\u{2588}")?;
+                }
+                let mut digits = String::new();
+                while let Ok(c) = self.next() {
+                    match c {
+                        '}' if !digits.is_empty() => break,
+                        c if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+                        _ => self.error_from(start, "Unicode escapes are one to six hex digits, followed by }")?,
+                    }
+                }
+                match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+                    Some(c) => c,
+                    None => self.error_from(start, &format!("\\u{{{digits}}} is not a unicode character"))?,
+                }
+            }
+            Ok(c) if !c.is_whitespace() => self.error_from(start, &format!("Unknown escape sequence \\{c}"))?,
+            _ => self.error_from(start, "Expected an escape sequence after \\")?,
+        }
+    }
+
+    /// Reads a number made of digits, underscores and at most one decimal
+    /// point.
     #[throws]
     pub fn number(&self, string: String) -> Token {
+        if let Some(c) = string.chars().find(|c| !(c.is_ascii_digit() || *c == '_' || *c == '.')) {
+            self.error(&format!("Unexpected {c:?} in number {string}"))?;
+        }
         let cleaned = string.replace("_", "");
         let is_frac = string.contains('.');
         if is_frac {
@@ -180,16 +310,32 @@ impl Lexer {
             if parts.len() > 2 {
                 self.error("More than one decimal point found in number")?;
             }
-            let value = str::parse::<f32>(&cleaned).unwrap();
-            Token::Literal(Value::Float(value))
+            match str::parse::<f64>(&cleaned) {
+                Ok(value) if value.is_finite() => Token::Literal(Value::Float(value)),
+                _ => self.error(&format!("Invalid number {string}"))?,
+            }
         } else {
-            let value = str::parse::<u8>(&cleaned).unwrap();
+            let value = match str::parse::<i64>(&cleaned) {
+                Ok(value) => value,
+                Err(_) => self.error(&format!("Number is too large, the largest is {}", i64::MAX))?,
+            };
             Token::Literal(Value::Int(value))
         }
     }
 
     pub fn error<T>(&self, msg: &str) -> Result<T, Error> {
-        Err(Error { msg: msg.to_string(), start: self.token_start, end: self.current })
+        Err(Error::new(msg, self.token_start, self.current))
+    }
+
+    /// An error from `start` up to the current character.
+    pub fn error_from<T>(&self, start: Position, msg: &str) -> Result<T, Error> {
+        Err(Error::new(msg, start, self.current))
+    }
+
+    /// An error pointing at just the first character of the current token,
+    /// for tokens that run on to the end of the file.
+    pub fn error_at_start<T>(&self, msg: &str) -> Result<T, Error> {
+        Err(Error::new(msg, self.token_start, self.token_start))
     }
 }
 
@@ -247,10 +393,10 @@ pub enum Token {
     Keyword(Keyword),
     Identifier(String),
 
-    EOF,
+    Eof,
 }
 
-#[derive(Debug, PartialEq, EnumString, Clone)]
+#[derive(Debug, PartialEq, EnumString, strum_macros::Display, Clone)]
 #[strum(serialize_all = "snake_case")]
 pub enum Keyword {
     Fn,
@@ -264,7 +410,11 @@ pub enum Keyword {
     For,
     In,
     If,
+    Then,
+    Else,
     Array,
+    Neighbourhood,
+    Halt,
 }
 
 pub mod macros {
@@ -289,6 +439,9 @@ pub mod macros {
         ($s:ident, $tokens:expr, $add_char:expr, $token:ident) => {{
             if $s.matches($add_char)? {
                 $tokens.push($s.wrap(Token::$token))
+            } else {
+                let first = $s.program[$s.index - 1];
+                $s.error(&format!("Unexpected {first:?}, did you mean {first}{}?", $add_char))?;
             }
         }};
     }
@@ -296,3 +449,130 @@ pub mod macros {
     pub(crate) use match_tokens;
     pub(crate) use match_two;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(program: &str) -> Vec<Token> {
+        let tokens = Lexer::new(program.to_string()).scan_tokens().unwrap();
+        tokens.into_iter().map(|token| token.inner).collect()
+    }
+
+    fn error(program: &str) -> Error {
+        Lexer::new(program.to_string()).scan_tokens().expect_err("expected an error")
+    }
+
+    #[test]
+    fn statement() {
+        let expected = [
+            Token::Underscore,
+            Token::Colon,
+            Token::At,
+            Token::Identifier("north".to_string()),
+            Token::GreaterThan,
+            Token::Literal(Value::Int(2)),
+            Token::And,
+            Token::Hash,
+            Token::OpenParen,
+            Token::Identifier("b".to_string()),
+            Token::CloseParen,
+            Token::NotEquals,
+            Token::Literal(Value::Float(1.5)),
+            Token::Pipeline,
+            Token::Keyword(Keyword::Halt),
+        ];
+        assert_eq!(tokens("_: @north >= 2 && #(b) != 1.5 |> halt // done"), expected);
+    }
+
+    #[test]
+    fn literals() {
+        let expected = [
+            Token::Literal(Value::Int(1_000_000)),
+            Token::Literal(Value::String("a b".to_string())),
+            Token::Literal(Value::Char('!')),
+        ];
+        assert_eq!(tokens("1_000_000 \"a b\" '!'"), expected);
+    }
+
+    #[test]
+    fn positions() {
+        let tokens = Lexer::new("!a = 0\n  ~a;3~".to_string()).scan_tokens().unwrap();
+        let tilde = &tokens[4];
+        assert_eq!(tilde.inner, Token::Tilde);
+        assert_eq!((tilde.start.line, tilde.start.col), (2, 4));
+    }
+
+    #[test]
+    fn unknown_character() {
+        let err = error("_ |> 1 ? 2");
+        assert_eq!(err.msg, "Unexpected character '?'");
+        assert_eq!((err.start.line, err.start.col), (1, 9));
+    }
+
+    #[test]
+    fn single_ampersand() {
+        let err = error("_: 1 & 2 |> 0");
+        assert_eq!(err.msg, "Unexpected '&', did you mean &&?");
+        assert_eq!(err.start.col, 7);
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let err = error("_ $ \"abc\n1 |> 2");
+        assert!(err.msg.starts_with("Unterminated string"), "{}", err.msg);
+        assert_eq!(err.end, err.start);
+        assert_eq!(err.start.col, 6);
+    }
+
+    #[test]
+    fn unterminated_char() {
+        assert!(error("_ $ 'a").msg.starts_with("Unterminated char literal"));
+        assert!(error("_ $ 'a\n").msg.starts_with("Unterminated char literal"));
+        assert!(error("_ $ '").msg.starts_with("Unterminated char literal"));
+        assert!(error("_ $ 'ab'").msg.starts_with("Char literals hold a single character"));
+        assert!(error("_ $ ''").msg.starts_with("Empty char literal"));
+    }
+
+    #[test]
+    fn large_numbers() {
+        assert_eq!(tokens("255 256 9223372036854775807").len(), 3);
+        let err = error("9223372036854775808");
+        assert!(err.msg.starts_with("Number is too large"), "{}", err.msg);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        assert_eq!(error("1.2.3").msg, "More than one decimal point found in number");
+        assert_eq!(error("12ab").msg, "Unexpected 'a' in number 12ab");
+        assert!(error("1e99").msg.starts_with("Unexpected 'e'"));
+    }
+
+    #[test]
+    fn escapes() {
+        let expected = [
+            Token::Literal(Value::String("a\tb\n\"c\" \\ \r\0".to_string())),
+            Token::Literal(Value::Char('\n')),
+            Token::Literal(Value::Char('\'')),
+            Token::Literal(Value::Char('"')),
+            Token::Literal(Value::Char('█')),
+            Token::Literal(Value::String("\u{1F600}'".to_string())),
+        ];
+        let program = r#""a\tb\n\"c\" \\ \r\0" '\n' '\'' '\"' '\u{2588}' "\u{1f600}\'""#;
+        assert_eq!(tokens(program), expected);
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        let err = error(r#"_ $ "a\qb""#);
+        assert_eq!(err.msg, "Unknown escape sequence \\q");
+        assert_eq!((err.start.col, err.end.col), (8, 9));
+
+        assert!(error(r"_ $ '\u2588'").msg.starts_with("Expected { after \\u"));
+        assert!(error(r"_ $ '\u{}'").msg.starts_with("Unicode escapes are one to six"));
+        assert!(error(r"_ $ '\u{12345678}'").msg.starts_with("Unicode escapes are one to six"));
+        assert!(error(r"_ $ '\u{zz}'").msg.starts_with("Unicode escapes are one to six"));
+        assert_eq!(error(r"_ $ '\u{d800}'").msg, "\\u{d800} is not a unicode character");
+        assert!(error(r#"_ $ "abc\"#).msg.starts_with("Expected an escape sequence"));
+    }
+}
@@ -1,65 +1,209 @@
-#![feature(never_type)]
-use lexer::Error;
+use std::fs::File;
+use std::io::{stderr, stdout, Write};
+use std::process::ExitCode;
+
+use cli::{CliError, Command, Options, RendererKind};
+use interpreter::{Halt, Interpreter};
+use diagnostics::Diagnostics;
+use lexer::{Error, Lexer};
+use parser::Parser;
+use render::{Image, Renderer, Terminal, Text};
 mod ast;
+mod cli;
+mod diagnostics;
+mod format;
 mod interpreter;
 mod lexer;
 mod parser;
 mod positioned;
+mod random;
+mod render;
 mod value;
 
-fn main() {
-    let arg = match std::env::args().nth(1) {
-        Some(p) => p,
-        None => {
-            eprintln!("No file supplied. Please specify a file to run.");
-            return;
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(CliError::Help) => {
+            print!("{}", CliError::Help);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprint!("{err}");
+            return ExitCode::from(2);
         }
     };
-    
-    let program = match std::fs::read_to_string(arg) {
+
+    let program = match std::fs::read_to_string(&options.file) {
         Ok(file) => file,
         Err(err) => {
             eprintln!("Failed to open file: {err:?}");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    match run_program(&program) {
-        Ok(_) => {},
-        Err(err) => eprintln!("{}", build_error(&program, err)),
+    let mut diagnostics = Diagnostics::new();
+    diagnostics.add(&options.file, &program);
+    for (name, value) in &options.constants {
+        diagnostics.add(&format!("--set {name}"), value);
+    }
+    for (name, value) in &options.settings {
+        diagnostics.add(&format!("--sys {name}"), value);
+    }
+
+    let result = match options.command {
+        Command::Run | Command::Export => match renderer(&options) {
+            Ok(mut renderer) => run_program(&program, &options, renderer.as_mut()),
+            Err(code) => return code,
+        },
+        Command::Check => load(&program, &options).map(|_| {
+            println!("{} is ok", options.file);
+            Halt::Stable
+        }),
+        Command::Fmt => fmt(&program, &options),
+        Command::DumpTokens => match Lexer::new(program.clone()).scan_tokens() {
+            Ok(tokens) => {
+                for token in tokens {
+                    println!("{}:{}\t{:?}", token.start.line, token.start.col - 1, token.inner);
+                }
+                Ok(Halt::Stable)
+            }
+            Err(err) => Err(vec![err]),
+        },
+        Command::DumpAst => parse(&program).map(|ast| {
+            for node in ast {
+                println!("{node:#?}");
+            }
+            Halt::Stable
+        }),
+    };
+
+    match result {
+        Ok(halt) => ExitCode::from(halt.code()),
+        Err(errors) => {
+            diagnostics.emit(&errors);
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn run_program(program: &str) -> Result<!, Error> {
-    let mut lexer = lexer::Lexer::new(program.to_string());
-    let tokens = lexer.scan_tokens()?;
+/// Picks the renderer for `run` and `export`, opening any output files.
+fn renderer(options: &Options) -> Result<Box<dyn Renderer>, ExitCode> {
+    if options.command == Command::Export {
+        return Ok(Box::new(Image::new(open_or(options.out.clone(), Box::new(stdout()))?)));
+    }
 
-    let mut parser = parser::Parser::new(tokens);
-    let ast = parser.parse()?;
+    Ok(match options.renderer {
+        RendererKind::Text => {
+            let grids = open_or(options.out.clone(), Box::new(stdout()))?;
+            let output = open_or(options.print_out.clone(), Box::new(stderr()))?;
+            Box::new(Text::new(grids, output, options.every))
+        }
+        RendererKind::Terminal => Box::new(Terminal::new()),
+    })
+}
 
-    let mut interp = interpreter::Interpreter::new(ast);
-    interp.interpret()?;
+/// Creates the file at `path` to write to, or uses `default` without one.
+fn open_or(path: Option<String>, default: Box<dyn Write>) -> Result<Box<dyn Write>, ExitCode> {
+    match path.map(File::create) {
+        Some(Ok(file)) => Ok(Box::new(file)),
+        Some(Err(err)) => {
+            eprintln!("Failed to create file: {err}");
+            Err(ExitCode::FAILURE)
+        }
+        None => Ok(default),
+    }
 }
 
-pub fn build_error(program: &str, error: Error) -> String {
-    let mut lines = program.lines();
+fn parse(program: &str) -> Result<Vec<positioned::Positioned<ast::Node>>, Vec<Error>> {
+    let tokens = Lexer::new(program.to_string()).scan_tokens()?;
+    Parser::new(tokens).parse()
+}
 
-    if error.start.end {
-        return String::from(error.msg);
+/// Lexes, parses and loads a program, applying the options that affect how
+/// it is loaded, without running any generations.
+fn load(program: &str, options: &Options) -> Result<Interpreter, Vec<Error>> {
+    let mut interp = Interpreter::new(parse(program)?).with_update_mode(options.update_mode);
+    if let Some(seed) = options.seed {
+        interp = interp.with_seed(seed);
+    }
+    if options.trace {
+        interp = interp.with_trace();
+    }
+    for (file, (name, value)) in options.constants.iter().enumerate() {
+        let value = constant(&interp, value).map_err(|err| {
+            let note = format!("in the value given to constant {name} with --set");
+            Error { file: file + 1, ..err.with_note(&note) }
+        })?;
+        interp.override_constant(name.clone(), value);
     }
 
-    let Error { msg, start, end } = error;
-    let error_point =
-        format!("{}{}", " ".repeat(start.col - 2), "^".repeat(end.col - start.col + 1));
-    let line = lines.nth(start.line - 1).unwrap();
-
-    format!(
-        "
-\x1b[91m\x1b[1mError:\x1b[m {}:
--> main.cell:{}:{} 
-| {}
-| \x1b[92m\x1b[1m{}
-    ",
-        msg, start.line, start.col, line, error_point
-    )
+    interp.load_instructions()?;
+    for (file, (name, _)) in options.constants.iter().enumerate() {
+        let note = format!("in the constant {name} given with --set");
+        interp.check_override(name).map_err(|err| Error { file: file + 1, ..err.with_note(&note) })?;
+    }
+
+    // Settings from the command line replace those in the program's sys block.
+    let first_setting = options.constants.len() + 1;
+    for (file, (name, value)) in options.settings.iter().enumerate() {
+        setting(&mut interp, name, value).map_err(|err| {
+            let note = format!("in the value given to setting {name} with --sys");
+            Error { file: first_setting + file, ..err.with_note(&note) }
+        })?;
+    }
+    if let Some(tick) = options.tick {
+        interp.set_tick(tick);
+    }
+    for rule in &options.disabled {
+        interp.disable_rule(rule).map_err(|err| err.with_note("in the rules given to --disable"))?;
+    }
+    interp.order_rules(&options.order).map_err(|err| err.with_note("in the rules given to --order"))?;
+    if let Some(generations) = options.generations {
+        interp.set_generation_limit(generations);
+    }
+    Ok(interp)
+}
+
+/// Evaluates the value given to `--set`.
+fn constant(interp: &Interpreter, value: &str) -> Result<value::Value, Error> {
+    let tokens = Lexer::new(value.to_string()).scan_tokens()?;
+    let expr = Parser::new(tokens).whole_expr()?;
+    interp.evaluate(&expr)
+}
+
+/// Applies a setting given with `--sys`.
+fn setting(interp: &mut Interpreter, name: &str, value: &str) -> Result<(), Error> {
+    let tokens = Lexer::new(value.to_string()).scan_tokens()?;
+    let expr = Parser::new(tokens).whole_expr()?;
+    interp.apply_setting(name, &expr).map_err(|err| err.or_at(expr.start, expr.end))
+}
+
+fn run_program(
+    program: &str,
+    options: &Options,
+    renderer: &mut dyn Renderer,
+) -> Result<Halt, Vec<Error>> {
+    let mut interp = load(program, options)?;
+    // Nobody watches a text or export run, so without a limit it runs a
+    // single generation rather than possibly never finishing.
+    let unwatched = options.command == Command::Export || options.renderer == RendererKind::Text;
+    if unwatched && interp.generation_limit().is_none() {
+        interp.set_generation_limit(1);
+    }
+    Ok(interp.run(renderer)?)
+}
+
+/// Prints the formatted program, or writes it back to its file with
+/// `--write`. Programs that don't parse are left alone.
+fn fmt(program: &str, options: &Options) -> Result<Halt, Vec<Error>> {
+    let tokens = Lexer::new(program.to_string()).scan_tokens()?;
+    Parser::new(tokens.clone()).parse()?;
+
+    let formatted = format::format(program, &tokens);
+    if options.write {
+        std::fs::write(&options.file, formatted).map_err(Error::from)?;
+    } else {
+        print!("{formatted}");
+    }
+    Ok(Halt::Stable)
 }
@@ -1,6 +1,6 @@
-use crate::ast::Node;
+use crate::ast::{Node, Shape, MAX_RADIUS, MAX_SIZE};
 use crate::value::Value;
-use crate::lexer::{Error, Token};
+use crate::lexer::{Error, Keyword, Token};
 use crate::positioned::{Position, Positioned};
 use fehler::{throws, throw};
 
@@ -21,77 +21,158 @@ impl Parser {
     }
 
     pub fn raw_error(token: &Positioned<Token>, msg: &str) -> Error {
-        Error { msg: msg.to_string(), start: token.start, end: token.end }
+        Error::new(msg, token.start, token.end)
     }
 
-    #[throws]
-    pub fn parse(&mut self) -> Vec<PNode> {
-        let mut ast = Vec::new();
+    /// Parses every statement, recovering from syntax errors so that all of
+    /// them can be reported at once.
+    pub fn parse(&mut self) -> Result<Vec<PNode>, Vec<Error>> {
+        let (mut ast, mut errors) = (Vec::new(), Vec::new());
 
         while !self.peek().is_eof() {
-            ast.push(self.statement()?);
+            let start = self.index;
+            match self.statement() {
+                Ok(statement) => ast.push(statement),
+                Err(error) => {
+                    errors.push(error);
+                    self.synchronise(start);
+                }
+            }
         }
 
-        ast
+        match errors.is_empty() {
+            true => Ok(ast),
+            false => Err(errors),
+        }
+    }
+
+    /// Skips past a statement that failed to parse, to the next line that
+    /// starts a new one.
+    ///
+    /// Lines starting with `!`, `|>`, `~`, `sys` or `neighbourhood` always
+    /// start a statement. Other lines do when they're outside any brackets
+    /// or memory block, or are indented no further than the failed
+    /// statement, so an unclosed bracket doesn't hide the rest of the file.
+    fn synchronise(&mut self, start: usize) {
+        let (mut depth, mut in_memory) = (0, false);
+        let mut index = start;
+        while let Some(token) = self.tokens.get(index) {
+            let new_line = index > start && self.tokens[index - 1].end.line < token.start.line;
+            if new_line && index >= self.index {
+                let keyword = matches!(
+                    token.inner,
+                    Token::Not | Token::Pipeline | Token::Keyword(Keyword::Sys | Keyword::Neighbourhood | Keyword::Rule)
+                ) || (token.is_tilde() && !in_memory);
+                let closes = matches!(token.inner, Token::CloseParen | Token::CloseBracket | Token::CloseBrace)
+                    || (token.is_tilde() && in_memory);
+                let unindented = token.start.col <= self.tokens[start].start.col;
+                if keyword || (!closes && ((depth == 0 && !in_memory) || unindented)) {
+                    break;
+                }
+            }
+
+            match token.inner {
+                Token::OpenParen | Token::OpenBracket | Token::OpenBrace => depth += 1,
+                Token::CloseParen | Token::CloseBracket | Token::CloseBrace => depth -= 1,
+                Token::Tilde => in_memory = !in_memory,
+                _ => {}
+            }
+            index += 1;
+        }
+        self.index = index;
     }
 
-    pub fn op_order(token: &Token) -> usize {
+    /// The precedence of a binary operator, and whether it is right
+    /// associative. Operators with a higher precedence bind more tightly:
+    ///
+    /// | Precedence | Operators              | Associativity |
+    /// |------------|------------------------|---------------|
+    /// | 1          | `\|\|`                 | left          |
+    /// | 2          | `&&`                   | left          |
+    /// | 3          | `==` `!=`              | left          |
+    /// | 4          | `<` `>` `<=` `>=`      | left          |
+    /// | 5          | `+` `-`                | left          |
+    /// | 6          | `*` `/` `%`            | left          |
+    /// | 7          | unary `!` `-`          | prefix        |
+    /// | 8          | `^`                    | right         |
+    pub fn precedence(token: &Token) -> Option<(u8, bool)> {
         use Token::*;
         match token {
-            Lesser | LesserThan | Greater | GreaterThan | Or | And | NotEquals | Equals | Mod => 0,
-            Plus | Minus => 1,
-            Asterisk | Slash => 2,
-            _ => 100,
+            Or => Some((1, false)),
+            And => Some((2, false)),
+            Equals | NotEquals => Some((3, false)),
+            Lesser | LesserThan | Greater | GreaterThan => Some((4, false)),
+            Plus | Minus => Some((5, false)),
+            Asterisk | Slash | Mod => Some((6, false)),
+            Carat => Some((8, true)),
+            _ => None,
         }
     }
 
-    pub fn is_op(token: &Token) -> bool {
-        Self::op_order(token) < 50
-    }
+    /// The precedence of the unary operators `!` and `-`, between `*` and
+    /// `^`, so `-a * b` is `(-a) * b` but `-a ^ b` is `-(a ^ b)`.
+    const UNARY_PRECEDENCE: u8 = 7;
 
     #[throws]
     pub fn expr(&mut self) -> PNode {
-        let left = self.call()?;
-
-        if Self::is_op(&self.peek().inner) {
-            let op = self.next().inner.clone();
-            let right = self.expr()?;
-
-            // ordering switching
-            if let Node::Binary { left: ref r_left, op: ref r_op, right: ref r_right } = *right {
-                if Self::op_order(&op) > Self::op_order(r_op) {
-                    return PNode::new_binary(
-                        PNode::new_binary(left, op, *r_left.clone()),
-                        r_op.clone(),
-                        *r_right.clone(),
-                    );
-                }
-            }
+        self.expr_above(0)?
+    }
+
+    /// Parses an expression that should be all there is, such as a value
+    /// given on the command line.
+    #[throws]
+    pub fn whole_expr(&mut self) -> PNode {
+        let expr = self.expr()?;
+        if !self.peek().is_eof() {
+            let next = self.next();
+            Self::error(&next, &format!("Expected the end of the value found {:?}", next.inner))?;
+        }
+        expr
+    }
 
-            return PNode::new_binary(left, op, right);
+    /// Parses an expression whose binary operators all have at least the
+    /// given precedence, by precedence climbing.
+    #[throws]
+    fn expr_above(&mut self, min: u8) -> PNode {
+        let mut left = self.unary()?;
+
+        while let Some((precedence, right_associative)) = Self::precedence(&self.peek()) {
+            if precedence < min {
+                break;
+            }
+            let op = self.next().inner;
+            let next_min = if right_associative { precedence } else { precedence + 1 };
+            let right = self.expr_above(next_min)?;
+            left = PNode::new_binary(left, op, right);
         }
 
         left
     }
 
+    #[throws]
+    fn unary(&mut self) -> PNode {
+        if !matches!(self.peek().inner, Token::Minus | Token::Not) {
+            return self.call()?;
+        }
+
+        let op = self.next();
+        let expr = Box::new(self.expr_above(Self::UNARY_PRECEDENCE)?);
+        let end = expr.end;
+        Positioned { inner: Node::Unary { op: op.inner, expr }, start: op.start, end }
+    }
+
     #[throws]
     pub fn call(&mut self) -> PNode {
         let mut expr = self.simple()?;
         let start = expr.start;
-        loop {
-            match self.peek().inner {
-                Token::OpenParen => {
-                    self.next();
-                    let args =
-                        if self.peek().is_close_paren() { Vec::new() } else { self.expr_list()? };
-                    let end = self.next_ensure(Token::CloseParen)?.end;
-                    expr = Positioned {
-                        inner: Node::Call { expr: Box::new(expr.clone()), args },
-                        start,
-                        end,
-                    };
-                }
-                _ => break,
+        while self.peek().is_open_paren() {
+            self.next();
+            let args = if self.peek().is_close_paren() { Vec::new() } else { self.expr_list()? };
+            let end = self.next_ensure(Token::CloseParen)?.end;
+            expr = Positioned {
+                inner: Node::Call { expr: Box::new(expr.clone()), args },
+                start,
+                end,
             };
         }
         expr
@@ -136,6 +217,9 @@ impl Parser {
             Token::Tilde => self.memory_statement()?,
             Token::Not => self.variable()?,
             Token::Pipeline => self.func_statement()?,
+            Token::Keyword(Keyword::Sys) => self.sys_statement()?,
+            Token::Keyword(Keyword::Neighbourhood) => self.neighbourhood_statement()?,
+            Token::Keyword(Keyword::Rule) => self.rule_statement()?,
             _ => {
                 self.backtrack();
                 let expr = self.expr()?;
@@ -145,45 +229,86 @@ impl Parser {
     }
 
     #[throws]
-    pub fn main_statement(&mut self, centre: PNode) -> PNode {
+    pub fn rule_statement(&mut self) -> PNode {
         let start = self.last().start;
+        let ident = self.peek();
+        let name = Positioned { inner: self.next_ident()?, start: ident.start, end: ident.end };
+        self.next_ensure(Token::OpenBrace)?;
+
+        let mut statements = Vec::new();
+        while !self.peek().is_close_brace() {
+            if self.peek().is_eof() {
+                throw!(Self::raw_error(&ident, &format!("Expected }} to close rule {}", name.inner)));
+            }
+            let centre = self.expr()?;
+            statements.push(self.main_statement(centre)?);
+        }
+        let end = self.next().end;
+        Positioned { inner: Node::Rule { name, statements }, start, end }
+    }
+
+    #[throws]
+    pub fn main_statement(&mut self, centre: PNode) -> PNode {
+        let start = centre.start;
         let centre = Box::new(centre);
         let mut conditional = None;
         if *self.peek() == Token::Colon {
             self.next();
             conditional = Some(Box::new(self.expr()?));
         }
-        let mut print;
+        let print;
+        let mut target = None;
         match *self.next() {
             Token::Pipeline => print = false,
             Token::Sign => print = true,
+            Token::Pipe => {
+                print = false;
+                target = Some(self.next_ident()?);
+                self.next_ensure(Token::Greater)?;
+            }
             _ => throw!(Self::raw_error(&self.last(), "Expected pipeline or sign")),
         }
         let result = Box::new(self.expr()?);
         let end = result.end;
-        Positioned { inner: Node::Main { centre, conditional, result, print }, start, end }
+        Positioned { inner: Node::Main { centre, conditional, result, print, target }, start, end }
     }
 
     #[throws]
     pub fn memory_statement(&mut self) -> PNode {
-        let start = self.last().start;
+        let open = self.last();
+        let start = open.start;
+        let mut layer = None;
+        if self.peek().is_identifier() && self.peek_ahead(1).is_colon() {
+            layer = Some(self.next_ident()?);
+            self.next();
+        }
+
         let mut data = vec![vec![]];
         let mut row = 0;
         while !self.peek().is_tilde() {
+            if self.peek().is_eof() {
+                throw!(Self::raw_error(&open, "Expected ~ to close memory"));
+            }
             let next = self.next();
             match next.inner {
-                Token::Identifier(ident) => {
-                    if self.peek().is_semicolon() {
-                        self.next();
-                        let count = self.next_number()?;
-                        for _ in 0..count {
-                            data[row].push(ident.clone());
-                        }
+                Token::Identifier(ident) if self.peek().is_semicolon() => {
+                    self.next();
+                    let count = self.next_number()?;
+                    if data[row].len().saturating_add(count) > MAX_SIZE {
+                        let msg = format!("A grid can be at most {MAX_SIZE} cells wide");
+                        Self::error(&self.last(), &msg)?;
+                    }
+                    for _ in 0..count {
+                        data[row].push(ident.clone());
                     }
                 },
                 Token::Pipe => { data.push(vec![]); row += 1; }
                 Token::Semicolon => {
                     let repeat = self.next_number()?;
+                    if data.len().saturating_add(repeat) > MAX_SIZE + 1 {
+                        let msg = format!("A grid can be at most {MAX_SIZE} cells high");
+                        Self::error(&self.last(), &msg)?;
+                    }
                     for _ in 1..repeat {
                         data.push(data[data.len() - 1].clone());
                         row += 1;
@@ -193,7 +318,78 @@ impl Parser {
             }
         }
         let end = self.next_ensure(Token::Tilde)?.end;
-        Positioned { inner: Node::Memory(data), start, end }
+        Positioned { inner: Node::Memory { layer, rows: data }, start, end }
+    }
+
+    /// Parses a settings block, `sys { name = value, ... }`. Commas between
+    /// settings are optional.
+    #[throws]
+    pub fn sys_statement(&mut self) -> PNode {
+        let start = self.last().start;
+        self.next_ensure(Token::OpenBrace)?;
+
+        let mut settings = Vec::new();
+        while !self.peek().is_close_brace() {
+            let name = self.next_ident()?;
+            self.next_ensure(Token::Define)?;
+            settings.push((name, self.expr()?));
+            if self.peek().is_comma() {
+                self.next();
+            }
+        }
+
+        let end = self.next_ensure(Token::CloseBrace)?.end;
+        Positioned { inner: Node::Sys(settings), start, end }
+    }
+
+    /// Parses `neighbourhood name = shape`, where the shape is `moore`,
+    /// `vonneumann` or `hex` with an optional radius, or a list of offsets
+    /// such as `[(1, 2), (-1, 2)]`.
+    #[throws]
+    pub fn neighbourhood_statement(&mut self) -> PNode {
+        let start = self.last().start;
+        let name = self.next_ident()?;
+        self.next_ensure(Token::Define)?;
+
+        let shape = if self.peek().is_open_bracket() {
+            self.next();
+            let mut offsets = Vec::new();
+            while !self.peek().is_close_bracket() {
+                self.next_ensure(Token::OpenParen)?;
+                let dx = self.next_offset()?;
+                self.next_ensure(Token::Comma)?;
+                let dy = self.next_offset()?;
+                self.next_ensure(Token::CloseParen)?;
+                offsets.push((dx, dy));
+                if !self.peek().is_comma() {
+                    break;
+                }
+                self.next();
+            }
+            self.next_ensure(Token::CloseBracket)?;
+            Shape::Offsets(offsets)
+        } else {
+            let shape = self.next();
+            let mut radius = 1;
+            if self.peek().is_open_paren() {
+                self.next();
+                radius = self.next_number()?;
+                if radius > MAX_RADIUS {
+                    let msg = format!("A neighbourhood's radius can be at most {MAX_RADIUS}, found {radius}");
+                    Self::error(&self.last(), &msg)?;
+                }
+                self.next_ensure(Token::CloseParen)?;
+            }
+            match &shape.inner {
+                Token::Identifier(shape) if shape == "moore" => Shape::Moore(radius),
+                Token::Identifier(shape) if shape == "vonneumann" => Shape::VonNeumann(radius),
+                Token::Identifier(shape) if shape == "hex" => Shape::Hex(radius),
+                _ => Self::error(&shape, "Expected moore, vonneumann, hex or a list of offsets")?,
+            }
+        };
+
+        let end = self.last().end;
+        Positioned { inner: Node::Neighbourhood { name, shape }, start, end }
     }
 
     #[throws]
@@ -219,7 +415,15 @@ impl Parser {
             Token::OpenBracket => {
                 let mut items = Vec::new();
                 if !self.peek().inner.is_close_bracket() {
-                    items = self.expr_list()?;
+                    let first = self.expr()?;
+                    if self.peek().inner == Token::Keyword(Keyword::For) {
+                        return self.comprehension(start, first)?;
+                    }
+                    items.push(first);
+                    while self.peek().is_comma() {
+                        self.next();
+                        items.push(self.expr()?);
+                    }
                 }
                 let end = self.next_ensure(Token::CloseBracket)?.end;
                 Positioned { inner: Node::Array(items), start, end }
@@ -235,8 +439,11 @@ impl Parser {
                         expr
                     }
                     Token::Comma => {
-                        self.backtrack();
-                        let list = self.expr_list()?;
+                        let mut list = vec![expr];
+                        while self.peek().is_comma() {
+                            self.next();
+                            list.push(self.expr()?);
+                        }
                         let end = self.next_ensure(Token::CloseParen)?.end;
                         Positioned { inner: Node::Tuple(list), start, end }
                     }
@@ -244,10 +451,49 @@ impl Parser {
                 }
             }
             Token::At => {
-                let start = self.last().start;
+                let mut layer = None;
+                if self.peek().is_identifier() && self.peek_ahead(1).is_period() {
+                    layer = Some(self.next_ident()?);
+                    self.next();
+                }
+
+                if self.peek().is_open_paren() {
+                    self.next();
+                    let dx = Box::new(self.expr()?);
+                    self.next_ensure(Token::Comma)?;
+                    let dy = Box::new(self.expr()?);
+                    let end = self.next_ensure(Token::CloseParen)?.end;
+                    return Positioned { inner: Node::Offset { layer, dx, dy }, start, end };
+                }
+
                 let name = self.next_ident()?;
+                let mut arg = None;
+                if self.peek().is_open_paren() {
+                    self.next();
+                    arg = Some(Box::new(self.expr()?));
+                    self.next_ensure(Token::CloseParen)?;
+                }
                 let end = self.last().end;
-                Positioned { inner: Node::Directional(name), start, end }
+                Positioned { inner: Node::Directional { layer, name, arg }, start, end }
+            }
+            Token::Keyword(Keyword::Halt) => {
+                let mut code = None;
+                let mut end = end;
+                if self.peek().is_open_paren() {
+                    self.next();
+                    code = Some(Box::new(self.expr()?));
+                    end = self.next_ensure(Token::CloseParen)?.end;
+                }
+                Positioned { inner: Node::Halt(code), start, end }
+            }
+            Token::Keyword(Keyword::If) => {
+                let condition = Box::new(self.expr()?);
+                self.next_keyword(Keyword::Then)?;
+                let then = Box::new(self.expr()?);
+                self.next_keyword(Keyword::Else)?;
+                let otherwise = Box::new(self.expr()?);
+                let end = otherwise.end;
+                Positioned { inner: Node::If { condition, then, otherwise }, start, end }
             }
             Token::Underscore => {
                 let last = self.last();
@@ -255,17 +501,60 @@ impl Parser {
                 let start = last.start;
                 Positioned { inner: Node::Literal(Value::Unknown), start, end }
             }
+            Token::Hash if self.peek().is_open_bracket() => {
+                let (binding, predicate, end) = self.neighbourhood_body()?;
+                Positioned { inner: Node::Count { binding, predicate }, start, end }
+            }
+            Token::Define if self.peek().is_open_bracket() => {
+                let (binding, expr, end) = self.neighbourhood_body()?;
+                Positioned { inner: Node::Total { binding, expr }, start, end }
+            }
             Token::Hash => {
                 let start = self.last().end;
-                self.next_ensure(Token::OpenParen)?;
-                let expr = Box::new(self.expr()?);
-                let end = self.next_ensure(Token::CloseParen)?.end;
+                let expr = Box::new(self.simple()?);
+                let end = expr.end;
                 Positioned { inner: Node::Sum(expr), start, end }
             }
             _ => Self::error(&token, &format!("Expected expression but got {}", token.inner))?,
         }
     }
 
+    /// Parses the rest of `[expr for name in source if filter]`, after `expr`.
+    #[throws]
+    fn comprehension(&mut self, start: Position, expr: PNode) -> PNode {
+        self.next_keyword(Keyword::For)?;
+        let name = self.next_ident()?;
+        self.next_keyword(Keyword::In)?;
+        let source = Box::new(self.expr()?);
+        let mut filter = None;
+        if self.peek().inner == Token::Keyword(Keyword::If) {
+            self.next();
+            filter = Some(Box::new(self.expr()?));
+        }
+        let end = self.next_ensure(Token::CloseBracket)?.end;
+        Positioned { inner: Node::Comprehension { expr: Box::new(expr), name, source, filter }, start, end }
+    }
+
+    /// Parses the bracketed part of a count or sum, either `[expr]` or
+    /// `[name in source: expr]`.
+    #[throws]
+    pub fn neighbourhood_body(&mut self) -> (Option<(String, Box<PNode>)>, Box<PNode>, Position) {
+        self.next_ensure(Token::OpenBracket)?;
+
+        let mut binding = None;
+        if self.peek().is_identifier() && self.peek_ahead(1).inner == Token::Keyword(Keyword::In) {
+            let name = self.next_ident()?;
+            self.next();
+            let source = Box::new(self.expr()?);
+            self.next_ensure(Token::Colon)?;
+            binding = Some((name, source));
+        }
+
+        let expr = Box::new(self.expr()?);
+        let end = self.next_ensure(Token::CloseBracket)?.end;
+        (binding, expr, end)
+    }
+
     #[throws]
     pub fn expr_list(&mut self) -> Vec<PNode> {
         let mut exprs = Vec::new();
@@ -280,7 +569,14 @@ impl Parser {
     pub fn peek(&mut self) -> Positioned<Token> {
         match self.tokens.get(self.index) {
             Some(token) => token.clone(),
-            None => Positioned { inner: Token::EOF, start: Position::end(), end: Position::end()},
+            None => Positioned { inner: Token::Eof, start: Position::end(), end: Position::end()},
+        }
+    }
+
+    pub fn peek_ahead(&mut self, distance: usize) -> Positioned<Token> {
+        match self.tokens.get(self.index + distance) {
+            Some(token) => token.clone(),
+            None => Positioned { inner: Token::Eof, start: Position::end(), end: Position::end()},
         }
     }
 
@@ -292,6 +588,14 @@ impl Parser {
         Ok(next)
     }
 
+    pub fn next_keyword(&mut self, keyword: Keyword) -> Result<Positioned<Token>, Error> {
+        let next = self.next();
+        if next.inner != Token::Keyword(keyword.clone()) {
+            return Self::error(&next, &format!("Expected {keyword} found {}", next.inner));
+        }
+        Ok(next)
+    }
+
     pub fn next_ident(&mut self) -> Result<String, Error> {
         let next = self.next();
         if let Token::Identifier(ident) = &next.inner {
@@ -301,28 +605,210 @@ impl Parser {
         }
     }
 
-    pub fn next_number(&mut self) -> Result<u8, Error> {
+    pub fn next_number(&mut self) -> Result<usize, Error> {
         let next = self.next();
-        if let Token::Literal(Value::Int(ident)) = &next.inner {
-            Ok(ident.clone())
+        if let Token::Literal(Value::Int(number)) = &next.inner {
+            Ok(*number as usize)
         } else {
             Self::error(&next, &format!("Expected identifier found {:?}", next.inner))
         }
     }
 
+    /// Reads a whole number with an optional leading minus sign.
+    pub fn next_offset(&mut self) -> Result<isize, Error> {
+        let negative = self.peek().is_minus();
+        if negative {
+            self.next();
+        }
+        let offset = self.next_number()? as isize;
+        Ok(if negative { -offset } else { offset })
+    }
+
     pub fn next(&mut self) -> Positioned<Token> {
         self.index += 1;
         match self.tokens.get(self.index - 1) {
             Some(token) => token.clone(),
-            None => Positioned { inner: Token::EOF, start: Position::end(), end: Position::end()},
+            None => Positioned { inner: Token::Eof, start: Position::end(), end: Position::end()},
         }
     }
 
     pub fn last(&mut self) -> Positioned<Token> {
-        self.tokens.get(self.index - 1).unwrap().clone()
+        match self.tokens.get(self.index - 1) {
+            Some(token) => token.clone(),
+            None => Positioned { inner: Token::Eof, start: Position::end(), end: Position::end()},
+        }
     }
 
     pub fn backtrack(&mut self) {
         self.index -= 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn errors(program: &str) -> Vec<Error> {
+        let tokens = Lexer::new(program.to_string()).scan_tokens().unwrap();
+        Parser::new(tokens).parse().expect_err("expected syntax errors")
+    }
+
+    /// The expression as an s-expression, to check how it was grouped.
+    fn grouping(expr: &str) -> String {
+        fn show(node: &PNode) -> String {
+            match &node.inner {
+                Node::Literal(value) => value.to_string(),
+                Node::Variable(name) => name.clone(),
+                Node::Binary { left, op, right } => format!("({op} {} {})", show(left), show(right)),
+                Node::Unary { op, expr } => format!("({op} {})", show(expr)),
+                Node::Comprehension { expr, name, source, filter } => match filter {
+                    Some(filter) => format!("(for {name} {} {} {})", show(source), show(expr), show(filter)),
+                    None => format!("(for {name} {} {})", show(source), show(expr)),
+                },
+                Node::If { condition, then, otherwise } => {
+                    format!("(if {} {} {})", show(condition), show(then), show(otherwise))
+                }
+                other => panic!("unexpected node {other:?}"),
+            }
+        }
+        let tokens = Lexer::new(expr.to_string()).scan_tokens().unwrap();
+        show(&Parser::new(tokens).expr().unwrap())
+    }
+
+    #[test]
+    fn binary_operators_are_left_associative() {
+        assert_eq!(grouping("a - b - c"), "(Minus (Minus a b) c)");
+        assert_eq!(grouping("a / b * c"), "(Asterisk (Slash a b) c)");
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            grouping("a * b + c * d == e"),
+            "(Equals (Plus (Asterisk a b) (Asterisk c d)) e)"
+        );
+        assert_eq!(grouping("a || b && c < d % e"), "(Or a (And b (Lesser c (Mod d e))))");
+    }
+
+    #[test]
+    fn power_is_right_associative_and_binds_tighter_than_unary() {
+        assert_eq!(grouping("a ^ b ^ c"), "(Carat a (Carat b c))");
+        assert_eq!(grouping("-a ^ b"), "(Minus (Carat a b))");
+        assert_eq!(grouping("!a && -b * c"), "(And (Not a) (Asterisk (Minus b) c))");
+    }
+
+    #[test]
+    fn if_expressions() {
+        assert_eq!(grouping("if a > b then a else b + c"), "(if (Greater a b) a (Plus b c))");
+        assert_eq!(grouping("if a then if b then c else d else e"), "(if a (if b c d) e)");
+        assert_eq!(grouping("1 + if a then b else c"), "(Plus Int (if a b c))");
+    }
+
+    #[test]
+    fn comprehensions() {
+        assert_eq!(grouping("[n * b for n in a if n > b]"), "(for n a (Asterisk n b) (Greater n b))");
+        assert_eq!(grouping("[n for n in [m for m in a]]"), "(for n (for m a m) n)");
+    }
+
+    #[test]
+    fn rule_blocks() {
+        let tokens = Lexer::new("rule grow {\n    0 |> 1\n    1 $ 'x'\n}\n_ |> 2".to_string()).scan_tokens().unwrap();
+        let ast = Parser::new(tokens).parse().unwrap();
+        assert_eq!(ast.len(), 2);
+        match &ast[0].inner {
+            Node::Rule { name, statements } => {
+                assert_eq!(name.inner, "grow");
+                assert_eq!(statements.len(), 2);
+            }
+            other => panic!("expected a rule, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unclosed_rule() {
+        let errors = errors("rule grow {\n    0 |> 1\n");
+        assert_eq!(errors[0].msg, "Expected } to close rule grow");
+        assert_eq!(errors[0].start.line, 1);
+    }
+
+    #[test]
+    fn tuples() {
+        let tokens = Lexer::new("(-1, a + b, c)".to_string()).scan_tokens().unwrap();
+        match Parser::new(tokens).expr().unwrap().inner {
+            Node::Tuple(items) => assert_eq!(items.len(), 3),
+            other => panic!("expected a tuple, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn neighbourhood_radius_is_capped() {
+        let errors = errors("neighbourhood big = moore(100000)\n");
+        assert_eq!(errors[0].msg, "A neighbourhood's radius can be at most 64, found 100000");
+        assert_eq!(errors[0].start.col, 28);
+    }
+
+    #[test]
+    fn unclosed_memory() {
+        let errors = errors("!a = 0\n~a;2");
+        assert_eq!(lines(&errors), [2]);
+        assert_eq!(errors[0].msg, "Expected ~ to close memory");
+    }
+
+    #[test]
+    fn memory_counts_are_capped() {
+        let errors = errors("~a;99999999999~\n~a;4000 b;100~\n~a;2;99999999999~\n");
+        assert_eq!(lines(&errors), [1, 2, 3]);
+        assert_eq!(errors[0].msg, "A grid can be at most 4096 cells wide");
+        assert_eq!(errors[0].start.col, 5);
+        assert_eq!(errors[1].msg, "A grid can be at most 4096 cells wide");
+        assert_eq!(errors[2].msg, "A grid can be at most 4096 cells high");
+        assert_eq!(errors[2].start.col, 7);
+    }
+
+    #[test]
+    fn whole_expressions() {
+        let tokens = Lexer::new("5 7".to_string()).scan_tokens().unwrap();
+        let err = Parser::new(tokens).whole_expr().unwrap_err();
+        assert_eq!(err.msg, "Expected the end of the value found Literal(Int(7))");
+        assert_eq!(err.start.col, 4);
+
+        let tokens = Lexer::new("(1, 2)".to_string()).scan_tokens().unwrap();
+        Parser::new(tokens).whole_expr().unwrap();
+    }
+
+    #[test]
+    fn if_without_else() {
+        let errors = errors("_ |> if a then b\n");
+        assert_eq!(errors[0].msg, "Expected else found Eof");
+    }
+
+    fn lines(errors: &[Error]) -> Vec<usize> {
+        errors.iter().map(|error| error.start.line).collect()
+    }
+
+    #[test]
+    fn reports_every_statement() {
+        let errors = errors("!a = 0\n_ |> )\n!b == 3\n0 $ 1\n_ : 1 2\n");
+        assert_eq!(lines(&errors), [2, 3, 5]);
+    }
+
+    #[test]
+    fn skips_statements_over_several_lines() {
+        let errors = errors("sys {\n    boundary = ,\n    generations = 3\n}\n_ |> )\n");
+        assert_eq!(lines(&errors), [2, 5]);
+    }
+
+    #[test]
+    fn unclosed_bracket_does_not_hide_later_errors() {
+        let errors = errors("|> f(a = a\n_ : 1 2\n");
+        assert_eq!(lines(&errors), [1, 2]);
+    }
+
+    #[test]
+    fn error_at_end_of_file() {
+        let errors = errors("x");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].start.end);
+    }
+}
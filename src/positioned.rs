@@ -61,10 +61,6 @@ impl Ord for Position {
 
 impl PartialOrd for Position {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.line.partial_cmp(&other.line) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.col.partial_cmp(&other.col)
+        Some(self.cmp(other))
     }
 }
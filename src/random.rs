@@ -0,0 +1,35 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A small xorshift generator, so runs can be repeated exactly by giving the
+/// same seed.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on a state of zero, and spreading the seed out
+        // keeps small seeds like 1 and 2 from starting off alike.
+        let state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        Self { state }
+    }
+
+    /// Seeds the generator from the clock.
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+        Self::new(nanos as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A float from 0 up to but not including 1.
+    pub fn next_float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
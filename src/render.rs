@@ -0,0 +1,309 @@
+use std::io::{stdout, Write};
+use std::thread;
+
+use crate::interpreter::Interpreter;
+use crate::lexer::Error;
+use fehler::throws;
+
+/// Shows the grid and `$` output of a running program.
+pub trait Renderer {
+    /// Called once with the initial grid, before any generations run.
+    fn start(&mut self, interp: &Interpreter) -> Result<(), Error>;
+
+    /// Called after each generation with the characters it printed.
+    fn generation(&mut self, interp: &Interpreter, output: &str) -> Result<(), Error>;
+
+    /// Called after each generation with how many cells each rule fired on,
+    /// when tracing, before `generation`.
+    fn trace(&mut self, trace: &str) -> Result<(), Error>;
+
+    /// Called once when the program halts.
+    fn finish(&mut self, interp: &Interpreter) -> Result<(), Error>;
+}
+
+/// A colour as its red, green and blue channels.
+pub type Colour = (u8, u8, u8);
+
+/// Animates the first layer in the terminal, two rows of cells to each line
+/// of text, with `$` output written below it, waiting the program's tick
+/// between generations.
+pub struct Terminal {
+    out: usize,
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        Self { out: 0 }
+    }
+
+    fn size(interp: &Interpreter) -> (usize, usize) {
+        let grid = interp.grid(0);
+        let height = (grid.len() as f32 / 2.0).round() as usize;
+        (grid[0].len(), height)
+    }
+
+    #[throws]
+    fn draw(&self, interp: &Interpreter) {
+        let (width, height) = Self::size(interp);
+        let palette = interp.palette();
+        let mut stdout = stdout().lock();
+
+        writeln!(stdout, "\x1b[{}A\r", height + 1)?;
+        for y in 0..height {
+            write!(stdout, "\x1b[0m ")?;
+            for x in 0..width {
+                let fg = interp.get_cell(x, y * 2);
+                let bg = interp.get_cell(x, y * 2 + 1);
+
+                write!(stdout, "{}{}▀", ansi_colour(fg, palette, true), ansi_colour(bg, palette, false))?;
+            }
+            writeln!(stdout, "\x1b[0m")?;
+        }
+        write!(stdout, "\r\x1b[{}C", self.out + 1)?;
+        stdout.flush()?;
+    }
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renderer for Terminal {
+    #[throws]
+    fn start(&mut self, interp: &Interpreter) {
+        let (width, height) = Self::size(interp);
+        for _ in 0..height {
+            println!(" {}", "▒".repeat(width));
+        }
+        self.draw(interp)?;
+    }
+
+    #[throws]
+    fn generation(&mut self, interp: &Interpreter, output: &str) {
+        for c in output.chars() {
+            print!("\r\x1b[{}C{}", self.out + 1, c);
+            self.out += 1;
+        }
+        self.draw(interp)?;
+        thread::sleep(interp.tick());
+    }
+
+    #[throws]
+    fn trace(&mut self, trace: &str) {
+        eprintln!("{trace}");
+    }
+
+    #[throws]
+    fn finish(&mut self, _interp: &Interpreter) {
+        println!("\r\x1b[{}C\x1b[0m", self.out + 1);
+    }
+}
+
+/// Writes grids as plain text, one row of space separated numbers to a line,
+/// with `$` output written separately.
+///
+/// Each grid starts with a `# generation N layer NAME` line and ends with a
+/// blank line. Traces are written between them as `# trace ...` lines.
+pub struct Text {
+    grids: Box<dyn Write>,
+    output: Box<dyn Write>,
+    every: bool,
+}
+
+impl Text {
+    /// Creates a text renderer writing only the final grids, or the grids of
+    /// every generation if `every` is set.
+    pub fn new(grids: Box<dyn Write>, output: Box<dyn Write>, every: bool) -> Self {
+        Self { grids, output, every }
+    }
+
+    #[throws]
+    fn write_grids(&mut self, interp: &Interpreter) {
+        for (layer, name) in interp.layers().iter().enumerate() {
+            writeln!(self.grids, "# generation {} layer {name}", interp.generation())?;
+            for row in interp.grid(layer) {
+                let row = row.iter().map(|cell| cell.to_string()).collect::<Vec<_>>();
+                writeln!(self.grids, "{}", row.join(" "))?;
+            }
+            writeln!(self.grids)?;
+        }
+    }
+}
+
+impl Renderer for Text {
+    #[throws]
+    fn start(&mut self, interp: &Interpreter) {
+        if self.every {
+            self.write_grids(interp)?;
+        }
+    }
+
+    #[throws]
+    fn generation(&mut self, interp: &Interpreter, output: &str) {
+        self.output.write_all(output.as_bytes())?;
+        if self.every {
+            self.write_grids(interp)?;
+        }
+    }
+
+    /// Writes the trace among the grids rather than with the `$` output, so
+    /// the two don't interleave.
+    #[throws]
+    fn trace(&mut self, trace: &str) {
+        writeln!(self.grids, "# trace {trace}")?;
+    }
+
+    #[throws]
+    fn finish(&mut self, interp: &Interpreter) {
+        if !self.every {
+            self.write_grids(interp)?;
+        }
+        self.grids.flush()?;
+        self.output.flush()?;
+    }
+}
+
+/// Draws nothing while the program runs, then writes the final grid of the
+/// first layer as a binary PPM image, one pixel to a cell.
+pub struct Image {
+    out: Box<dyn Write>,
+}
+
+impl Image {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self { out }
+    }
+}
+
+impl Renderer for Image {
+    #[throws]
+    fn start(&mut self, _interp: &Interpreter) {}
+
+    #[throws]
+    fn generation(&mut self, _interp: &Interpreter, _output: &str) {}
+
+    #[throws]
+    fn trace(&mut self, trace: &str) {
+        eprintln!("{trace}");
+    }
+
+    #[throws]
+    fn finish(&mut self, interp: &Interpreter) {
+        let grid = interp.grid(0);
+        write!(self.out, "P6\n{} {}\n255\n", grid[0].len(), grid.len())?;
+        for row in grid {
+            for cell in row {
+                let (r, g, b) = colour(*cell, interp.palette());
+                self.out.write_all(&[r, g, b])?;
+            }
+        }
+        self.out.flush()?;
+    }
+}
+
+pub fn ansi_colour(v: i64, palette: &[Colour], fg: bool) -> String {
+    let (r, g, b) = colour(v, palette);
+    format!("\x1b[{};2;{r};{g};{b}m", if fg { "38" } else { "48" })
+}
+
+/// Maps a cell value to a colour. Values with a colour in the palette use
+/// it. Otherwise values from 0 to 255 are shades of grey, larger values cycle
+/// through the hues, and negative values are shades of blue.
+pub fn colour(v: i64, palette: &[Colour]) -> Colour {
+    if let Some(colour) = usize::try_from(v).ok().and_then(|v| palette.get(v)) {
+        return *colour;
+    }
+    if let Ok(grey) = u8::try_from(v) {
+        return (grey, grey, grey);
+    }
+    if v < 0 {
+        return (0, 0, v.unsigned_abs().min(255) as u8);
+    }
+
+    let hue = (v - 256) % (255 * 6);
+    let rising = (hue % 255) as u8;
+    let falling = 255 - rising;
+    match hue / 255 {
+        0 => (255, rising, 0),
+        1 => (falling, 255, 0),
+        2 => (0, 255, rising),
+        3 => (0, falling, 255),
+        4 => (rising, 0, 255),
+        _ => (255, 0, falling),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer whose output can still be read once a renderer owns it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn load(program: &str) -> Interpreter {
+        let tokens = Lexer::new(program.to_string()).scan_tokens().unwrap();
+        let mut interp = Interpreter::new(Parser::new(tokens).parse().unwrap());
+        interp.load_instructions().unwrap();
+        interp
+    }
+
+    #[test]
+    fn image() {
+        let interp = load("sys {\n    palette = [[1, 2, 3]]\n}\n!a = 0\n!b = 255\n~a;1 b;2|b;1 a;2~");
+        let out = Shared::default();
+        let mut image = Image::new(Box::new(out.clone()));
+        image.finish(&interp).unwrap();
+
+        let mut expected = b"P6\n3 2\n255\n".to_vec();
+        expected.extend([1, 2, 3, 255, 255, 255, 255, 255, 255, 255, 255, 255, 1, 2, 3, 1, 2, 3]);
+        assert_eq!(*out.0.borrow(), expected);
+    }
+
+    #[test]
+    fn traces_are_written_with_the_grids() {
+        let mut interp = load("sys {\n    generations = 2\n}\n!a = 0\n~a;2~\n_ $ 'x'").with_trace();
+        let (grids, output) = (Shared::default(), Shared::default());
+        let mut text = Text::new(Box::new(grids.clone()), Box::new(output.clone()), true);
+        interp.run(&mut text).unwrap();
+
+        let grids = String::from_utf8(grids.0.take()).unwrap();
+        let lines = grids.lines().filter(|line| line.starts_with('#')).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "# generation 0 layer main",
+                "# trace generation 1: line 6=2",
+                "# generation 1 layer main",
+                "# trace generation 2: line 6=2",
+                "# generation 2 layer main",
+            ]
+        );
+        assert_eq!(output.0.take(), b"xxxx");
+    }
+
+    #[test]
+    fn colours() {
+        assert_eq!(colour(7, &[]), (7, 7, 7));
+        assert_eq!(colour(-300, &[]), (0, 0, 255));
+        assert_eq!(colour(256, &[]), (255, 0, 0));
+        assert_eq!(colour(1, &[(0, 0, 0), (9, 8, 7)]), (9, 8, 7));
+    }
+}
@@ -1,22 +1,22 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
 
 use strum_macros::Display;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Display)]
 pub enum Value {
-    Int(u8),
-    Float(f32),
+    Int(i64),
+    Float(f64),
     String(String),
     Char(char),
     Array(Vec<Value>),
     Bool(bool),
-    Function(fn(HashMap<String, Value>) -> Value),
-    Unit,
     Unknown,
 }
 
-type Error = String;
-
+/// Integer arithmetic wraps around on overflow, like two's complement
+/// hardware. `%` is euclidean, so its result is never negative. Integer
+/// division and modulo by zero have no result. An integer to a negative power
+/// is a float.
 impl Value {
     pub fn and(&self, other: &Value) -> Option<Value> {
         Some(Value::Bool(self.as_bool() && other.as_bool()))
@@ -30,42 +30,41 @@ impl Value {
         match self {
             Value::Int(i) => *i != 0,
             Value::Float(f) => *f >= 1.0,
-            Value::String(s) => s.len() != 0,
+            Value::String(s) => !s.is_empty(),
             Value::Char(c) => *c != '\0',
-            Value::Array(a) => a.len() != 0,
+            Value::Array(a) => !a.is_empty(),
             Value::Bool(b) => *b,
-            Value::Function(_) => true,
-            Value::Unit => false,
             Value::Unknown => true,
         }
     }
 
     pub fn as_char(&self) -> char {
-        self.as_num() as char
+        self.as_num().and_then(|num| u32::try_from(num).ok()).and_then(char::from_u32).unwrap_or(char::REPLACEMENT_CHARACTER)
     }
 
     pub fn mul(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i * j)),
-            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f32) * j)),
+            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i.wrapping_mul(*j))),
+            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f64) * j)),
             (Self::Float(i), Self::Float(j)) => Some(Value::Float(*i * j)),
-            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i * *j as f32)),
+            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i * *j as f64)),
             (Self::Array(a), Self::Int(i)) => Some(Self::repeat_array(a.clone(), *i)),
-            (Self::Bool(b), Self::Int(i)) => Some(Value::Int(i * if *b { 1 } else { 0 })),
+            (Self::Bool(b), Self::Int(i)) => Some(Value::Int(if *b { *i } else { 0 })),
             _ => None,
         }
     }
 
-    pub fn repeat_array(array: Vec<Value>, repeat: u8) -> Value {
-        Value::Array(vec![array; repeat as usize].iter().flat_map(|i| i.clone()).collect())
+    pub fn repeat_array(array: Vec<Value>, repeat: i64) -> Value {
+        Value::Array(vec![array; repeat.max(0) as usize].iter().flat_map(|i| i.clone()).collect())
     }
 
     pub fn div(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i / j)),
-            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f32) / j)),
+            (Self::Int(_), Self::Int(0)) => None,
+            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i.wrapping_div(*j))),
+            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f64) / j)),
             (Self::Float(i), Self::Float(j)) => Some(Value::Float(*i / j)),
-            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i / *j as f32)),
+            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i / *j as f64)),
             _ => None,
         }
     }
@@ -73,8 +72,8 @@ impl Value {
     pub fn add(&self, other: &Value) -> Option<Value> {
         match (self, other) {
             (Self::Int(i), Self::Int(j)) => Some(Value::Int(i.wrapping_add(*j))),
-            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f32) + j)),
-            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i + *j as f32)),
+            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f64) + j)),
+            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i + *j as f64)),
             (Self::Float(i), Self::Float(j)) => Some(Value::Float(*i + j)),
             (Self::String(s), Self::String(t)) => Some(Value::String(format!("{}{}", s, t))),
             _ => None,
@@ -83,36 +82,104 @@ impl Value {
 
     pub fn sub(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i - j)),
-            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f32) - j)),
+            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i.wrapping_sub(*j))),
+            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f64) - j)),
             (Self::Float(i), Self::Float(j)) => Some(Value::Float(*i - j)),
-            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i - *j as f32)),
+            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i - *j as f64)),
+            _ => None,
+        }
+    }
+
+    pub fn neg(&self) -> Option<Value> {
+        match self {
+            Self::Int(i) => Some(Value::Int(i.wrapping_neg())),
+            Self::Float(f) => Some(Value::Float(-f)),
+            _ => None,
+        }
+    }
+
+    pub fn not(&self) -> Value {
+        Value::Bool(!self.as_bool())
+    }
+
+    pub fn pow(&self, other: &Value) -> Option<Value> {
+        match (self, other) {
+            (Self::Int(i), Self::Int(j)) if *j < 0 => Some(Value::Float((*i as f64).powi(Self::exponent(*j)))),
+            (Self::Int(i), Self::Int(j)) => Some(Value::Int(Self::wrapping_pow(*i, *j as u64))),
+            (Self::Int(i), Self::Float(j)) => Some(Value::Float((*i as f64).powf(*j))),
+            (Self::Float(i), Self::Int(j)) => Some(Value::Float(i.powi(Self::exponent(*j)))),
+            (Self::Float(i), Self::Float(j)) => Some(Value::Float(i.powf(*j))),
+            _ => None,
+        }
+    }
+
+    /// Clamps an exponent to those `powi` takes. Floats overflow to infinity
+    /// or underflow to zero long before the clamped exponents are reached.
+    fn exponent(exponent: i64) -> i32 {
+        exponent.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+
+    /// Exponentiation by squaring, so large exponents are quick and wrap
+    /// around like the other integer operators.
+    fn wrapping_pow(mut base: i64, mut exponent: u64) -> i64 {
+        let mut result: i64 = 1;
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = result.wrapping_mul(base);
+            }
+            base = base.wrapping_mul(base);
+            exponent >>= 1;
+        }
+        result
+    }
+
+    /// Orders two values of the same kind, or an int and a float by their
+    /// numeric value.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Int(i), Self::Float(j)) => (*i as f64).partial_cmp(j),
+            (Self::Float(i), Self::Int(j)) => i.partial_cmp(&(*j as f64)),
+            _ if std::mem::discriminant(self) == std::mem::discriminant(other) => self.partial_cmp(other),
+            _ => None,
+        }
+    }
+
+    /// Whether two values are equal, comparing an int and a float by their
+    /// numeric value like `compare` does.
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Self::Int(_), Self::Float(_)) | (Self::Float(_), Self::Int(_)) => {
+                self.compare(other).is_some_and(Ordering::is_eq)
+            }
+            _ => self == other,
+        }
+    }
+
+    pub fn as_offset(&self) -> Option<isize> {
+        match self {
+            Value::Int(i) => Some(*i as isize),
+            Value::Float(f) => Some(f.round() as isize),
             _ => None,
         }
     }
 
     pub fn modulus(&self, other: &Value) -> Option<Value> {
         match (self, other) {
-            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i % j)),
+            (Self::Int(_), Self::Int(0)) => None,
+            (Self::Int(i), Self::Int(j)) => Some(Value::Int(i.wrapping_rem_euclid(*j))),
             _ => None,
         }
     }
 
-    pub fn as_num(&self) -> u8 {
+    /// The value as a number, if it has one. Strings and arrays don't.
+    pub fn as_num(&self) -> Option<i64> {
         match self {
-            Value::Int(i) => *i,
-            Value::Float(f) => f.round() as u8,
-            Value::Char(c) => *c as u8,
-            Value::Bool(b) => {
-                if *b {
-                    0
-                } else {
-                    1
-                }
-            }
-            Value::Unit => 0,
-            Value::Unknown => 0,
-            _ => panic!("Expected number"),
+            Value::Int(i) => Some(*i),
+            Value::Float(f) => Some(f.round() as i64),
+            Value::Char(c) => Some(*c as i64),
+            Value::Bool(b) => Some(*b as i64),
+            Value::Unknown => Some(0),
+            Value::String(_) | Value::Array(_) => None,
         }
     }
 }